@@ -0,0 +1,187 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::functions::fn_print_helper;
+use crate::interpreter::Block;
+use crate::parser::{Object, ParseError};
+use crate::tokenizer::Span;
+
+/// The builtin condition types and their parent types
+const CONDITION_TYPES: &[(&str, &str)] = &[
+    ("error", "condition"),
+    ("warning", "condition"),
+    ("simple-condition", "condition"),
+    ("simple-error", "error"),
+    ("simple-warning", "warning"),
+    ("type-error", "error"),
+    ("unbound-variable", "error"),
+    ("undefined-function", "error"),
+    ("program-error", "error"),
+    ("arithmetic-error", "error"),
+    ("division-by-zero", "arithmetic-error"),
+    ("file-error", "error"),
+    ("parse-error", "error"),
+    ("end-of-file", "error"),
+    ("storage-condition", "error"),
+];
+
+/// A signalled situation, such as an error, that handlers can select by type
+#[derive(Debug, PartialEq)]
+pub struct Condition {
+    /// The condition's type followed by each of its ancestors, ending with `condition`
+    pub types: Vec<String>,
+    pub message: String,
+}
+
+impl Condition {
+    /// Creates a condition of type `kind`. Builtin types keep their usual ancestors and
+    /// other types are treated as subtypes of `parent`.
+    pub fn new(kind: &str, parent: &str, message: &str) -> Condition {
+        let mut types = vec![kind.to_string()];
+        let mut current = if CONDITION_TYPES.iter().any(|(name, _)| *name == kind) { kind } else { parent };
+        if current != kind {
+            types.push(current.to_string());
+        }
+        while let Some((_, parent)) = CONDITION_TYPES.iter().find(|(name, _)| *name == current) {
+            types.push(parent.to_string());
+            current = parent;
+        }
+        Condition { types, message: message.to_string() }
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.types[0]
+    }
+
+    pub fn is_a(&self, kind: &str) -> bool {
+        self.types.iter().any(|name| name == kind)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind() {
+            "simple-error" | "simple-warning" | "simple-condition" => write!(f, "{}", self.message),
+            kind => write!(f, "{}: {}", kind, self.message),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LispError {
+    UnboundVariable(String),
+    UndefinedFunction(String),
+    WrongType { expected: &'static str, found: Object },
+    ArityMismatch { name: String, min: usize, max: Option<usize>, found: usize },
+    /// A form to evaluate that is a dotted list, such as `(f . x)`
+    ImproperList(Object),
+    DivisionByZero,
+    Overflow,
+    /// Evaluation nested deeper than the limit
+    StackDepthExceeded(usize),
+    FileNotFound(String),
+    /// `read` found no form before the end of its input
+    EndOfFile,
+    ParseError(ParseError),
+    /// A `return-from` unwinding to the nearest enclosing block with this name
+    ReturnFrom { block: Rc<Block>, value: Box<Object> },
+    /// A `throw` unwinding to the nearest `catch` with this tag
+    Throw { tag: Box<Object>, value: Box<Object> },
+    /// A condition raised by `error`, or by `signal` or `warn` when a handler is waiting for it
+    Condition(Rc<Condition>),
+    /// An error raised while evaluating or reading the form at `span`
+    Located { span: Span, error: Box<LispError> },
+}
+
+impl LispError {
+    /// Records where the error happened. An error that already has a position keeps it, since
+    /// that is the innermost one. Non-local exits pass through unchanged so that an enclosing
+    /// `block` or `catch` still recognises them.
+    pub fn at(self, span: &Span) -> LispError {
+        match self {
+            LispError::ReturnFrom { .. } | LispError::Throw { .. } | LispError::Located { .. } => self,
+            _ => LispError::Located { span: span.clone(), error: Box::new(self) },
+        }
+    }
+
+    /// The condition that handlers see for this error, or `None` for non-local exits, which
+    /// can't be handled
+    pub fn condition(&self) -> Option<Rc<Condition>> {
+        let kind = match self {
+            LispError::Condition(condition) => return Some(condition.clone()),
+            LispError::Located { error, .. } => return error.condition(),
+            LispError::ReturnFrom { .. } | LispError::Throw { .. } => return None,
+            LispError::UnboundVariable(_) => "unbound-variable",
+            LispError::UndefinedFunction(_) => "undefined-function",
+            LispError::WrongType { .. } => "type-error",
+            LispError::ArityMismatch { .. } | LispError::ImproperList(_) => "program-error",
+            LispError::DivisionByZero => "division-by-zero",
+            LispError::Overflow => "arithmetic-error",
+            LispError::StackDepthExceeded(_) => "storage-condition",
+            LispError::FileNotFound(_) => "file-error",
+            LispError::ParseError(_) => "parse-error",
+            LispError::EndOfFile => "end-of-file",
+        };
+        Some(Rc::new(Condition::new(kind, "error", &self.to_string())))
+    }
+}
+
+impl From<ParseError> for LispError {
+    fn from(error: ParseError) -> Self {
+        LispError::ParseError(error)
+    }
+}
+
+impl fmt::Display for LispError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LispError::UnboundVariable(name) => write!(f, "Unbound variable: {}", name),
+            LispError::UndefinedFunction(name) => write!(f, "Undefined function: {}", name),
+            LispError::WrongType { expected, found } => {
+                write!(f, "Wrong type: expected {}, got {}", expected, fn_print_helper(found))
+            }
+            LispError::ArityMismatch { name, min, max, found } => {
+                let expected = match max {
+                    Some(max) if max == min => min.to_string(),
+                    Some(max) => format!("{} to {}", min, max),
+                    None => format!("at least {}", min),
+                };
+                write!(f, "Wrong number of arguments to {}: expected {}, got {}", name, expected, found)
+            }
+            LispError::ImproperList(form) => write!(f, "Cannot evaluate dotted list {}", fn_print_helper(form)),
+            LispError::DivisionByZero => write!(f, "Division by zero"),
+            LispError::Overflow => write!(f, "Integer overflow"),
+            LispError::StackDepthExceeded(limit) => write!(f, "Stack depth exceeded: limit is {}", limit),
+            LispError::FileNotFound(path) => write!(f, "File not found: {}", path),
+            LispError::ParseError(error) => write!(f, "Parse error: {}", error),
+            LispError::EndOfFile => write!(f, "End of file"),
+            LispError::ReturnFrom { block, .. } => write!(f, "No block named {} to return from", block.name),
+            LispError::Throw { tag, .. } => write!(f, "No catch for tag {}", fn_print_helper(tag)),
+            LispError::Condition(condition) => write!(f, "{}", condition),
+            LispError::Located { span, error } => write!(f, "{}\n    at {}", error, span),
+        }
+    }
+}
+
+impl std::error::Error for LispError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::Atom;
+
+    #[test]
+    fn condition_types() {
+        let condition = Condition::new("division-by-zero", "error", "Division by zero");
+        assert_eq!(condition.types, vec!["division-by-zero", "arithmetic-error", "error", "condition"]);
+        let condition = Condition::new("my-error", "error", "oops");
+        assert_eq!(condition.types, vec!["my-error", "error", "condition"]);
+        assert!(condition.is_a("error") && !condition.is_a("warning"));
+        assert_eq!(condition.to_string(), "my-error: oops");
+
+        let error = LispError::DivisionByZero.at(&Span { file: None, line: 1, column: 1 });
+        assert_eq!(error.condition().unwrap().kind(), "division-by-zero");
+        let tag = Box::new(Object::Atom(Atom::T));
+        assert_eq!(LispError::Throw { tag: tag.clone(), value: tag }.condition(), None);
+    }
+}
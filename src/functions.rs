@@ -1,169 +1,131 @@
-use crate::interpreter::{eval, Stack};
-use crate::parser::{ConsCell, Object};
-use crate::tokenizer;
-use crate::tokenizer::{Atom, MyFloat, Token};
-
-pub fn fn_less_than(a: &Object, b: &Object) -> Object {
-    let a = match a {
-        Object::Atom(Atom::Integer(number)) => *number as f64,
-        Object::Atom(Atom::Float(MyFloat(number))) => *number,
-        _ => panic!("Cannot compare non-number")
-    };
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::rc::Rc;
 
-    let b = match b {
-        Object::Atom(Atom::Integer(number)) => *number as f64,
-        Object::Atom(Atom::Float(MyFloat(number))) => *number,
-        _ => panic!("Cannot compare non-number")
-    };
+use crate::error::{Condition, LispError};
+use crate::gc;
+use crate::pprint::{pretty_print, right_margin};
+use crate::interpreter::{call_function, eval, locate, Block, Environment, Tail, BUILTINS};
+use crate::number::{format_float, Number};
+use crate::parser::{parse_all, parse_source, ConsCell, Function, Lambda, Object, ParseError};
+use crate::tokenizer::Atom;
 
-    if a < b {
-        Object::Atom(Atom::T)
-    } else {
-        Object::Atom(Atom::Nil)
-    }
+fn compare(a: &Object, b: &Object) -> Result<Option<Ordering>, LispError> {
+    Ok(Number::from_object(a)?.compare(&Number::from_object(b)?))
 }
 
-pub fn fn_greater_than(a: &Object, b: &Object) -> Object {
-    let a = match a {
-        Object::Atom(Atom::Integer(number)) => *number as f64,
-        Object::Atom(Atom::Float(MyFloat(number))) => *number,
-        _ => panic!("Cannot compare non-number")
-    };
-
-    let b = match b {
-        Object::Atom(Atom::Integer(number)) => *number as f64,
-        Object::Atom(Atom::Float(MyFloat(number))) => *number,
-        _ => panic!("Cannot compare non-number")
-    };
-
-    if a > b {
+fn bool_obj(value: bool) -> Object {
+    if value {
         Object::Atom(Atom::T)
     } else {
         Object::Atom(Atom::Nil)
     }
 }
 
-pub fn fn_greater_than_or_equal(a: &Object, b: &Object) -> Object {
-    if fn_greater_than(a, b) == Object::Atom(Atom::T) || fn_equal(a, b) == Object::Atom(Atom::T) {
-        Object::Atom(Atom::T)
-    } else {
-        Object::Atom(Atom::Nil)
-    }
+pub fn fn_less_than(a: &Object, b: &Object) -> Result<Object, LispError> {
+    Ok(bool_obj(compare(a, b)? == Some(Ordering::Less)))
 }
 
-pub fn fn_less_than_or_equal(a: &Object, b: &Object) -> Object {
-    if fn_less_than(a, b) == Object::Atom(Atom::T) || fn_equal(a, b) == Object::Atom(Atom::T) {
-        Object::Atom(Atom::T)
-    } else {
-        Object::Atom(Atom::Nil)
-    }
+pub fn fn_greater_than(a: &Object, b: &Object) -> Result<Object, LispError> {
+    Ok(bool_obj(compare(a, b)? == Some(Ordering::Greater)))
 }
 
-pub fn fn_and(expressions: &Object, stack: &mut Stack) -> Object {
-    let expressions_list = match expressions {
-        Object::List(list) => list,
-        _ => panic!("AND requires a list")
-    };
+pub fn fn_greater_than_or_equal(a: &Object, b: &Object) -> Result<Object, LispError> {
+    Ok(bool_obj(matches!(compare(a, b)?, Some(Ordering::Greater | Ordering::Equal))))
+}
+
+pub fn fn_less_than_or_equal(a: &Object, b: &Object) -> Result<Object, LispError> {
+    Ok(bool_obj(matches!(compare(a, b)?, Some(Ordering::Less | Ordering::Equal))))
+}
 
-    for expr in expressions_list {
-        if eval(expr, stack) == Object::Atom(Atom::Nil) {
-            return Object::Atom(Atom::Nil);
+pub fn fn_and(expressions: &[Object], env: &Environment) -> Result<Object, LispError> {
+    for expr in expressions {
+        if eval(expr, env)? == Object::Atom(Atom::Nil) {
+            return Ok(Object::Atom(Atom::Nil));
         }
     }
-    Object::Atom(Atom::T)
+    Ok(Object::Atom(Atom::T))
 }
 
-pub fn fn_load(filename: &Object, stack: &mut Stack) -> Object {
+pub fn fn_load(filename: &Object, env: &Environment) -> Result<Object, LispError> {
     if let Object::Atom(Atom::String(filename)) = filename {
-        let contents = std::fs::read_to_string(filename).unwrap();
-        let tokens = tokenizer::tokenize_expr(&contents);
-        let tokens = vec![]
-            .into_iter()
-            .chain(vec![Token::LParen])
-            .chain(tokens.into_iter())
-            .chain(vec![Token::RParen].into_iter())
-            .collect::<Vec<_>>();
-        let parsed = crate::parser::parse(&mut tokens.iter().peekable());
-        fn_eval_multiple(&parsed, stack);
+        let contents = std::fs::read_to_string(filename)
+            .map_err(|_| LispError::FileNotFound(filename.clone()))?;
+        for form in parse_source(&contents, Some(filename))? {
+            eval(&form.object, env).map_err(|error| error.at(&form.span))?;
+        }
 
-        Object::Atom(Atom::T)
+        Ok(Object::Atom(Atom::T))
     } else {
-        panic!("Load requires filename as string")
+        Err(LispError::WrongType { expected: "string", found: filename.clone() })
     }
 }
 
-fn int_from_obj(obj: &Object) -> i32 {
-    match obj {
-        Object::Atom(Atom::Integer(number)) => *number,
-        Object::Atom(Atom::Float(MyFloat(number))) => *number as i32,
-        _ => panic!("Cannot convert non-number to int")
-    }
+pub fn fn_floor(num: &Object, div: &Object) -> Result<Object, LispError> {
+    let quotient = Number::from_object(num)?.div(&Number::from_object(div)?)?;
+    Ok(quotient.floor()?.into_object())
 }
 
-pub fn fn_floor(num: &Object, div: &Object) -> Object {
-    let num = int_from_obj(num);
-    let div = int_from_obj(div);
-    Object::Atom(Atom::Integer(num / div))
-}
+/// The remainder of flooring division, taking the sign of the divisor
+pub fn fn_mod(num: &Object, m: &Object) -> Result<Object, LispError> {
+    let num = Number::from_object(num)?;
+    let m = Number::from_object(m)?;
+    let quotient = num.div(&m)?.floor()?;
 
-pub fn fn_mod(num: &Object, m: &Object) -> Object {
-    let num = int_from_obj(num);
-    let m = int_from_obj(m);
+    Ok(num.sub(&m.mul(&quotient)).into_object())
+}
 
-    Object::Atom(Atom::Integer(num % m))
+fn first_arg<'a>(name: &str, vec: &'a [Object]) -> Result<&'a Object, LispError> {
+    vec.first().ok_or(LispError::ArityMismatch {
+        name: name.to_string(),
+        min: 1,
+        max: None,
+        found: 0,
+    })
 }
 
-pub fn fn_subtract(vec: &Vec<Object>) -> Object {
-    let mut result = match vec.first().unwrap() {
-        Object::Atom(Atom::Integer(number)) => *number as f64,
-        Object::Atom(Atom::Float(MyFloat(number))) => *number,
-        _ => panic!("Cannot subtract non-number")
-    };
+pub fn fn_subtract(vec: &[Object]) -> Result<Object, LispError> {
+    let first = Number::from_object(first_arg("-", vec)?)?;
+    if vec.len() == 1 {
+        return Ok(Number::Integer(0).sub(&first).into_object());
+    }
 
+    let mut result = first;
     for item in vec.iter().skip(1) {
-        match item {
-            Object::Atom(Atom::Integer(number)) => result -= *number as f64,
-            Object::Atom(Atom::Float(MyFloat(number))) => result -= number,
-            _ => panic!("Cannot subtract non-number")
-        }
+        result = result.sub(&Number::from_object(item)?);
     }
-    Object::Atom(Atom::Float(MyFloat(result)))
+    Ok(result.into_object())
 }
 
-pub fn fn_add(vec: &Vec<Object>) -> Object {
-    let mut sum = 0.0;
+pub fn fn_add(vec: &[Object]) -> Result<Object, LispError> {
+    let mut sum = Number::Integer(0);
     for item in vec {
-        match item {
-            Object::Atom(Atom::Integer(number)) => sum += *number as f64,
-            Object::Atom(Atom::Float(MyFloat(number))) => sum += number,
-            _ => panic!("Cannot add non-number")
-        }
+        sum = sum.add(&Number::from_object(item)?);
     }
-    Object::Atom(Atom::Float(MyFloat(sum)))
+    Ok(sum.into_object())
 }
 
-pub fn fn_multiply(vec: &Vec<Object>) -> Object {
-    let mut product = 1.0;
+pub fn fn_multiply(vec: &[Object]) -> Result<Object, LispError> {
+    let mut product = Number::Integer(1);
     for item in vec {
-        product *= match item {
-            Object::Atom(Atom::Integer(number)) => *number as f64,
-            Object::Atom(Atom::Float(MyFloat(number))) => *number,
-            _ => panic!("Cannot multiply non-number")
-        }
+        product = product.mul(&Number::from_object(item)?);
     }
-    Object::Atom(Atom::Float(MyFloat(product)))
+    Ok(product.into_object())
 }
 
-pub fn fn_divide(vec: &Vec<Object>) -> Object {
-    let mut product = 1.0;
-    for item in vec {
-        product /= match item {
-            Object::Atom(Atom::Integer(number)) => *number as f64,
-            Object::Atom(Atom::Float(MyFloat(number))) => *number,
-            _ => panic!("Cannot multiply non-number")
-        }
+pub fn fn_divide(vec: &[Object]) -> Result<Object, LispError> {
+    let first = Number::from_object(first_arg("/", vec)?)?;
+    if vec.len() == 1 {
+        return Ok(Number::Integer(1).div(&first)?.into_object());
+    }
+
+    let mut quotient = first;
+    for item in vec.iter().skip(1) {
+        quotient = quotient.div(&Number::from_object(item)?)?;
     }
-    Object::Atom(Atom::Float(MyFloat(product)))
+    Ok(quotient.into_object())
 }
 
 pub fn fn_equal(a: &Object, b: &Object) -> Object {
@@ -188,6 +150,18 @@ pub fn fn_eq(a: &Object, b: &Object) -> Object {
                 _ => Object::Atom(Atom::Nil)
             }
         }
+        Object::Atom(Atom::Integer(a_number)) => {
+            match b {
+                Object::Atom(Atom::Integer(b_number)) if a_number == b_number => Object::Atom(Atom::T),
+                _ => Object::Atom(Atom::Nil)
+            }
+        }
+        Object::Atom(Atom::BigInt(a_number)) => {
+            match b {
+                Object::Atom(Atom::BigInt(b_number)) if a_number == b_number => Object::Atom(Atom::T),
+                _ => Object::Atom(Atom::Nil)
+            }
+        }
         Object::Atom(Atom::Nil) => {
             match b {
                 Object::Atom(Atom::Nil) => Object::Atom(Atom::T),
@@ -200,119 +174,954 @@ pub fn fn_eq(a: &Object, b: &Object) -> Object {
                 _ => Object::Atom(Atom::Nil)
             }
         }
+        // Conses are the same object only if they are the same cell
+        Object::Cons(a_cell) => {
+            match b {
+                Object::Cons(b_cell) if Rc::ptr_eq(a_cell, b_cell) => Object::Atom(Atom::T),
+                _ => Object::Atom(Atom::Nil)
+            }
+        }
+        // `#'car` makes a new builtin object each time, so builtins are the same by name
+        Object::Function(a_function) => {
+            match (a_function.as_ref(), b) {
+                (Function::Builtin(a_name), Object::Function(b_function)) if matches!(b_function.as_ref(), Function::Builtin(b_name) if a_name == b_name) => Object::Atom(Atom::T),
+                (_, Object::Function(b_function)) if Rc::ptr_eq(a_function, b_function) => Object::Atom(Atom::T),
+                _ => Object::Atom(Atom::Nil)
+            }
+        }
+        Object::Condition(a_condition) => {
+            match b {
+                Object::Condition(b_condition) if Rc::ptr_eq(a_condition, b_condition) => Object::Atom(Atom::T),
+                _ => Object::Atom(Atom::Nil)
+            }
+        }
         _ => Object::Atom(Atom::Nil)
     }
 }
 
-pub fn fn_cond(clauses: &Object, stack: &mut Stack) -> Object {
-    let clause_list = match clauses {
-        Object::List(list) => list,
-        _ => panic!("Clauses is not a list")
+/// Returns the first non-nil value, leaving the last expression as a tail call
+pub fn fn_or(expressions: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let Some((last, init)) = expressions.split_last() else {
+        return Ok(Tail::Value(Object::Atom(Atom::Nil)));
     };
-    for clause in clause_list {
-        let clause_vec = match clause {
-            Object::List(list) => list,
-            _ => panic!("Clause is not a list")
+    for expr in init {
+        let value = eval(expr, env)?;
+        if value != Object::Atom(Atom::Nil) {
+            return Ok(Tail::Value(value));
+        }
+    }
+    Ok(Tail::Call(last.clone(), env.clone()))
+}
+
+pub fn fn_if(test: &Object, then: &Object, otherwise: Option<&Object>, env: &Environment) -> Result<Tail, LispError> {
+    if eval(test, env)? != Object::Atom(Atom::Nil) {
+        Ok(Tail::Call(then.clone(), env.clone()))
+    } else {
+        match otherwise {
+            Some(otherwise) => Ok(Tail::Call(otherwise.clone(), env.clone())),
+            None => Ok(Tail::Value(Object::Atom(Atom::Nil))),
+        }
+    }
+}
+
+/// Evaluates the body of `when` if the test is true, or of `unless` if it is false
+pub fn fn_when(test: &Object, body: &[Object], env: &Environment, expected: bool) -> Result<Tail, LispError> {
+    if (eval(test, env)? != Object::Atom(Atom::Nil)) == expected {
+        eval_body(body, env)
+    } else {
+        Ok(Tail::Value(Object::Atom(Atom::Nil)))
+    }
+}
+
+/// Evaluates every expression and returns the value of the one at `index`
+pub fn fn_prog_n(index: usize, expressions: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let mut result = Object::Atom(Atom::Nil);
+    for (i, expr) in expressions.iter().enumerate() {
+        let value = eval(expr, env)?;
+        if i == index {
+            result = value;
+        }
+    }
+    Ok(result)
+}
+
+pub fn fn_not(object: &Object) -> Object {
+    bool_obj(*object == Object::Atom(Atom::Nil))
+}
+
+/// Evaluates the body of the first clause whose test is true, leaving its last form as a
+/// tail call. A clause without a body produces the value of its test.
+pub fn fn_cond(clauses: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    for clause in clauses {
+        let Some(list) = clause.to_vec().filter(|list| !list.is_empty()) else {
+            return Err(LispError::WrongType { expected: "cond clause", found: clause.clone() });
         };
+        let (test, body) = (&list[0], &list[1..]);
 
-        if eval(&clause_vec[0], stack) != Object::Atom(Atom::Nil) {
-            return fn_eval_multiple(&clause, stack);
+        let value = eval(test, env)?;
+        if value != Object::Atom(Atom::Nil) {
+            if body.is_empty() {
+                return Ok(Tail::Value(value));
+            }
+            return eval_body(body, env);
         }
     }
-    Object::Atom(Atom::Nil)
+    Ok(Tail::Value(Object::Atom(Atom::Nil)))
 }
 
-fn bind_actuals(formals: &Object, actuals: &Object, stack: &mut Stack) -> Stack {
-    let formals_list = match formals {
-        Object::List(list) => list,
-        _ => panic!("Formals is not a list")
+/// Splits an `&optional` or `&key` parameter, written `x` or `(x [default [supplied-p]])`
+fn parameter_spec(formal: &Object) -> Result<(String, Option<Object>, Option<String>), LispError> {
+    let Object::Cons(_) = formal else {
+        return Ok((symbol_name(formal)?.to_string(), None, None));
     };
+    match formal.to_vec().as_deref() {
+        Some([name]) => Ok((symbol_name(name)?.to_string(), None, None)),
+        Some([name, default]) => Ok((symbol_name(name)?.to_string(), Some(default.clone()), None)),
+        Some([name, default, supplied]) => {
+            Ok((symbol_name(name)?.to_string(), Some(default.clone()), Some(symbol_name(supplied)?.to_string())))
+        }
+        _ => Err(LispError::WrongType { expected: "parameter", found: formal.clone() }),
+    }
+}
+
+/// Binds actual arguments to a lambda list of required parameters, followed by any
+/// `&optional`, `&rest` (or `&body`) and `&key` parameters. Defaults are evaluated in order in
+/// the new frame, so they can refer to earlier parameters.
+fn bind_actuals(name: &str, formals: &Object, actuals: &[Object], env: &Environment) -> Result<Environment, LispError> {
+    let mut required = Vec::new();
+    let mut optional = Vec::new();
+    let mut rest = None;
+    let mut keys = Vec::new();
+    let mut section = "";
+    let formals = list_items(formals)?;
+    for formal in &formals {
+        match formal {
+            Object::Atom(Atom::Symbol(symbol)) if matches!(&symbol[..], "&optional" | "&rest" | "&body" | "&key") => {
+                section = symbol;
+            }
+            _ => match section {
+                "" => required.push(symbol_name(formal)?),
+                "&optional" => optional.push(parameter_spec(formal)?),
+                "&rest" | "&body" if rest.is_none() => rest = Some(symbol_name(formal)?),
+                "&key" => keys.push(parameter_spec(formal)?),
+                _ => return Err(LispError::WrongType { expected: "lambda list keyword", found: formal.clone() }),
+            },
+        }
+    }
+
+    let max = if rest.is_some() || !keys.is_empty() { None } else { Some(required.len() + optional.len()) };
+    if actuals.len() < required.len() || max.is_some_and(|max| actuals.len() > max) {
+        return Err(LispError::ArityMismatch {
+            name: name.to_string(),
+            min: required.len(),
+            max,
+            found: actuals.len(),
+        });
+    }
+
+    let fn_env = env.extend();
+    let (required_actuals, actuals) = actuals.split_at(required.len());
+    for (symbol, actual) in required.iter().zip(required_actuals) {
+        fn_env.define(symbol, actual.clone());
+    }
+
+    let (optional_actuals, actuals) = actuals.split_at(optional.len().min(actuals.len()));
+    for (i, (symbol, default, supplied)) in optional.into_iter().enumerate() {
+        let value = match optional_actuals.get(i) {
+            Some(actual) => actual.clone(),
+            None => let_value(default.as_ref(), &fn_env)?,
+        };
+        fn_env.define(&symbol, value);
+        if let Some(supplied) = supplied {
+            fn_env.define(&supplied, bool_obj(i < optional_actuals.len()));
+        }
+    }
+
+    if let Some(rest) = rest {
+        fn_env.define(rest, fn_list(actuals));
+    }
+
+    if !keys.is_empty() {
+        if actuals.len() % 2 != 0 {
+            return Err(LispError::WrongType { expected: "keyword arguments", found: fn_list(actuals) });
+        }
+        for key in actuals.iter().step_by(2) {
+            let known = keys.iter().any(|(symbol, _, _)| *key == Object::Atom(Atom::Symbol(format!(":{}", symbol))));
+            if !known {
+                return Err(LispError::WrongType { expected: "known keyword", found: key.clone() });
+            }
+        }
+        for (symbol, default, supplied) in keys {
+            let keyword = Object::Atom(Atom::Symbol(format!(":{}", symbol)));
+            let actual = actuals.chunks(2).find(|pair| pair[0] == keyword).map(|pair| pair[1].clone());
+            let is_supplied = actual.is_some();
+            let value = match actual {
+                Some(actual) => actual,
+                None => let_value(default.as_ref(), &fn_env)?,
+            };
+            fn_env.define(&symbol, value);
+            if let Some(supplied) = supplied {
+                fn_env.define(&supplied, bool_obj(is_supplied));
+            }
+        }
+    }
+    Ok(fn_env)
+}
 
-    let actuals_list = match actuals {
-        Object::List(list) => list,
-        _ => panic!("Actuals is not a list")
+/// Evaluates all but the last expression, returning the last one as a tail call. A last
+/// expression that is an atom is evaluated straight away, which keeps an error in it inside
+/// the form the body belongs to.
+pub fn eval_body(expressions: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let Some((last, init)) = expressions.split_last() else {
+        return Ok(Tail::Value(Object::Atom(Atom::Nil)));
     };
+    for expr in init {
+        eval(expr, env)?;
+    }
+    match last {
+        Object::Cons(_) => Ok(Tail::Call(last.clone(), env.clone())),
+        _ => eval(last, env).map(Tail::Value),
+    }
+}
 
-    let mut fn_stack = stack.clone();
-    for (i, formal) in formals_list.iter().enumerate() {
-        if let Object::Atom(Atom::Symbol(symbol)) = formal {
-            let actual = &actuals_list[i];
-            let evaluated_actual = eval(actual, stack);
-            fn_stack.push(Atom::Symbol(symbol.clone()), evaluated_actual);
-        } else {
-            panic!("Non-symbol found in formals")
+pub fn fn_eval_multiple(expressions: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let mut last = Object::Atom(Atom::Nil);
+    for expr in expressions {
+        last = eval(expr, env)?;
+    }
+    Ok(last)
+}
+
+pub fn fn_apply(function: &Object, actuals: &Object, env: &Environment) -> Result<Object, LispError> {
+    match actuals.to_vec() {
+        Some(list) => fn_funcall(function, &list, env),
+        None => Err(LispError::WrongType { expected: "list", found: actuals.clone() })
+    }
+}
+
+/// Calls a function object, or the global function named by a symbol
+pub fn fn_funcall(function: &Object, actuals: &[Object], env: &Environment) -> Result<Object, LispError> {
+    match function {
+        Object::Atom(Atom::Symbol(symbol)) => call_function(symbol, actuals, env),
+        Object::Function(rc) => {
+            match rc.as_ref() {
+                Function::Builtin(name) => call_function(name, actuals, env),
+                Function::Lambda(lambda) => call_lambda(lambda, actuals)?.into_value(),
+                Function::Macro(_) => Err(LispError::WrongType { expected: "function", found: function.clone() }),
+            }
         }
+        _ => Err(LispError::WrongType { expected: "function", found: function.clone() })
+    }
+}
+
+/// Binds a lambda's arguments and evaluates its body, leaving the last form as a tail call
+fn call_lambda(lambda: &Lambda, actuals: &[Object]) -> Result<Tail, LispError> {
+    let name = lambda.name.as_deref().unwrap_or("lambda");
+    let fn_env = bind_actuals(name, &lambda.formals, actuals, &lambda.env)?;
+    let block = lambda.name.as_ref().map(|name| fn_env.define_block(name));
+    // Errors in body forms that are lists already have a position, but a variable in the body
+    // is found through the definition
+    let body = eval_body(&lambda.body, &fn_env).map_err(|error| locate(error, &lambda.source));
+    match block {
+        Some(block) => block_tail(block, body),
+        None => body,
     }
-    fn_stack
 }
 
-pub fn fn_eval_multiple(expressions: &Object, stack: &mut Stack) -> Object {
-    if let Object::List(list) = expressions {
-        let mut last = Object::Atom(Atom::Nil);
-        for expr in list {
-            last = eval(expr, stack);
+/// Like `fn_funcall`, but a lambda's last body form is left as a tail call
+pub fn funcall_tail(function: &Object, actuals: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    if let Object::Function(rc) = function {
+        if let Function::Lambda(lambda) = rc.as_ref() {
+            return call_lambda(lambda, actuals);
         }
-        last
-    } else {
-        panic!("Eval multiple requires a list of expressions");
     }
+    fn_funcall(function, actuals, env).map(Tail::Value)
 }
 
-pub fn fn_apply(fn_name: &Object, actuals: &Object, stack: &mut Stack) -> Object {
-    if let Object::Atom(Atom::Symbol(symbol)) = fn_name {
-        let mut eval_list = vec![Object::Atom(Atom::Symbol(symbol.clone()))];
-        if let Object::List(list) = actuals {
-            eval_list.extend(list.iter().cloned());
-            eval(&Object::List(eval_list), stack)
-        } else {
-            panic!("Function object is not a list")
+pub fn fn_apply_user(fn_name: &str, actuals: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let fn_object = env.find_function(fn_name)
+        .ok_or_else(|| LispError::UndefinedFunction(fn_name.to_string()))?;
+    funcall_tail(&fn_object, actuals, env)
+}
+
+/// Creates a closure over the current environment. `source` is the form that defines it.
+pub fn fn_lambda(name: Option<&str>, formals: &Object, body: &[Object], source: &Object, env: &Environment) -> Object {
+    Object::function(Function::Lambda(Lambda {
+        name: name.map(str::to_string),
+        formals: formals.clone(),
+        body: body.to_vec(),
+        source: source.clone(),
+        env: env.clone(),
+    }))
+}
+
+/// Looks up the function named by a symbol, or closes over a lambda expression
+pub fn fn_function(name: &Object, env: &Environment) -> Result<Object, LispError> {
+    match name {
+        Object::Atom(Atom::Symbol(symbol)) => {
+            if let Some(function) = env.find_function(symbol) {
+                Ok(function)
+            } else if BUILTINS.contains(&&symbol[..]) {
+                Ok(Object::function(Function::Builtin(symbol.clone())))
+            } else {
+                Err(LispError::UndefinedFunction(symbol.clone()))
+            }
+        }
+        Object::Cons(cell) if cell.car() == Object::Atom(Atom::Symbol(String::from("lambda"))) => {
+            eval(name, env)
         }
+        _ => Err(LispError::WrongType { expected: "function name", found: name.clone() })
+    }
+}
+
+/// Defines a global function. Its body is a block named after the function, which the trampoline
+/// in `eval` keeps open across the body's tail call.
+pub fn fn_defun(name: &Object, formals: &Object, body: &[Object], source: &Object, env: &Environment) -> Result<Object, LispError> {
+    if let Object::Atom(Atom::Symbol(symbol)) = name {
+        let function = fn_lambda(Some(symbol), formals, body, source, env);
+        env.define_function(symbol, function);
+        Ok(Object::Atom(Atom::Symbol(symbol.clone())))
+    } else {
+        Err(LispError::WrongType { expected: "symbol", found: name.clone() })
+    }
+}
+
+pub fn fn_defmacro(name: &Object, formals: &Object, body: &[Object], source: &Object, env: &Environment) -> Result<Object, LispError> {
+    if let Object::Atom(Atom::Symbol(symbol)) = name {
+        let expander = Lambda {
+            name: Some(symbol.clone()),
+            formals: formals.clone(),
+            body: body.to_vec(),
+            source: source.clone(),
+            env: env.clone(),
+        };
+        env.define_function(symbol, Object::function(Function::Macro(expander)));
+        Ok(Object::Atom(Atom::Symbol(symbol.clone())))
     } else {
-        panic!("Cannot apply a non-symbol")
+        Err(LispError::WrongType { expected: "symbol", found: name.clone() })
+    }
+}
+
+/// The expander of the macro named `name`, if there is one
+pub fn find_macro(name: &str, env: &Environment) -> Option<Rc<Function>> {
+    match env.find_function(name) {
+        Some(Object::Function(function)) if matches!(function.as_ref(), Function::Macro(_)) => Some(function),
+        _ => None,
+    }
+}
+
+/// Runs a macro's expander on the unevaluated argument forms
+pub fn expand_macro(expander: &Function, args: &[Object]) -> Result<Object, LispError> {
+    let Function::Macro(lambda) = expander else {
+        unreachable!("expand_macro called with a function");
+    };
+    let name = lambda.name.as_deref().unwrap_or("macro");
+    let macro_env = bind_actuals(name, &lambda.formals, args, &lambda.env)?;
+    fn_eval_multiple(&lambda.body, &macro_env)
+}
+
+/// Expands `form` once if it is a macro call, otherwise returns it unchanged
+pub fn fn_macroexpand_1(form: &Object, env: &Environment) -> Result<Object, LispError> {
+    if let Some(list) = form.to_vec() {
+        if let Some((Object::Atom(Atom::Symbol(symbol)), args)) = list.split_first() {
+            if let Some(expander) = find_macro(symbol, env) {
+                return expand_macro(&expander, args);
+            }
+        }
+    }
+    Ok(form.clone())
+}
+
+/// Expands `form` repeatedly until it is no longer a macro call
+pub fn fn_macroexpand(form: &Object, env: &Environment) -> Result<Object, LispError> {
+    let mut form = form.clone();
+    loop {
+        let expanded = fn_macroexpand_1(&form, env)?;
+        if expanded == form {
+            return Ok(form);
+        }
+        form = expanded;
     }
 }
 
-pub fn fn_apply_user(fn_name: &Object, actuals: &Object, stack: &mut Stack) -> Object {
-    if let Object::Atom(Atom::Symbol(symbol)) = fn_name {
-        let fn_object = stack.find(&Atom::Symbol(symbol.clone())).expect(format!("Function {} not found", symbol).as_str());
-        if let Object::List(list) = fn_object {
-            let mut list_iter = list.iter();
-            let formals = list_iter.next().unwrap();
-            let body = list_iter.next().unwrap();
-            fn_eval_multiple(body, &mut bind_actuals(formals, actuals, stack))
+/// The elements of a list, where `nil` is the empty list
+fn list_items(object: &Object) -> Result<Vec<Object>, LispError> {
+    object.to_vec().ok_or_else(|| LispError::WrongType { expected: "list", found: object.clone() })
+}
+
+/// Splits a `let` binding, written `x`, `(x)` or `(x value)`, into its name and value form
+fn let_binding(binding: &Object) -> Result<(String, Option<Object>), LispError> {
+    let (name, value) = match binding {
+        Object::Cons(_) => match binding.to_vec().as_deref() {
+            Some([name]) => (name.clone(), None),
+            Some([name, value]) => (name.clone(), Some(value.clone())),
+            _ => return Err(LispError::WrongType { expected: "let binding", found: binding.clone() }),
+        },
+        _ => (binding.clone(), None),
+    };
+    match name {
+        Object::Atom(Atom::Symbol(symbol)) => Ok((symbol, value)),
+        _ => Err(LispError::WrongType { expected: "symbol", found: name }),
+    }
+}
+
+fn let_value(value: Option<&Object>, env: &Environment) -> Result<Object, LispError> {
+    value.map_or(Ok(Object::Atom(Atom::Nil)), |value| eval(value, env))
+}
+
+/// Evaluates every value in the enclosing environment, then binds them all in a new frame
+pub fn fn_let(bindings: &Object, body: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let let_env = env.extend();
+    for binding in list_items(bindings)? {
+        let (name, value) = let_binding(&binding)?;
+        let_env.define(&name, let_value(value.as_ref(), env)?);
+    }
+    eval_body(body, &let_env)
+}
+
+/// Binds each value in turn, so later values can refer to earlier bindings
+pub fn fn_let_star(bindings: &Object, body: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let let_env = env.extend();
+    for binding in list_items(bindings)? {
+        let (name, value) = let_binding(&binding)?;
+        let value = let_value(value.as_ref(), &let_env)?;
+        let_env.define(&name, value);
+    }
+    eval_body(body, &let_env)
+}
+
+/// Defines local functions for `flet`, or for `labels` when `recursive` is set so that the
+/// functions can call themselves and each other
+pub fn fn_flet(definitions: &Object, body: &[Object], env: &Environment, recursive: bool) -> Result<Tail, LispError> {
+    let flet_env = env.extend();
+    let closure_env = if recursive { &flet_env } else { env };
+    for definition in list_items(definitions)? {
+        let Some(list) = definition.to_vec().filter(|list| list.len() >= 2) else {
+            return Err(LispError::WrongType { expected: "function definition", found: definition.clone() });
+        };
+        let (name, formals, fn_body) = (&list[0], &list[1], &list[2..]);
+        let Object::Atom(Atom::Symbol(name)) = name else {
+            return Err(LispError::WrongType { expected: "symbol", found: name.clone() });
+        };
+        flet_env.define_local_function(name, fn_lambda(Some(name), formals, fn_body, &definition, closure_env));
+    }
+    eval_body(body, &flet_env)
+}
+
+fn is_true(object: &Object) -> bool {
+    *object != Object::Atom(Atom::Nil)
+}
+
+fn symbol_name(object: &Object) -> Result<&str, LispError> {
+    match object {
+        Object::Atom(Atom::Symbol(symbol)) => Ok(symbol),
+        _ => Err(LispError::WrongType { expected: "symbol", found: object.clone() }),
+    }
+}
+
+/// Splits the `(var value [result])` header of `dotimes` and `dolist`
+fn iteration_spec(spec: &Object) -> Result<(String, Object, Option<Object>), LispError> {
+    match spec.to_vec().as_deref() {
+        Some([var, value]) => Ok((symbol_name(var)?.to_string(), value.clone(), None)),
+        Some([var, value, result]) => Ok((symbol_name(var)?.to_string(), value.clone(), Some(result.clone()))),
+        _ => Err(LispError::WrongType { expected: "iteration spec", found: spec.clone() }),
+    }
+}
+
+fn iteration_result(result: Option<&Object>, env: &Environment) -> Result<Object, LispError> {
+    result.map_or(Ok(Object::Atom(Atom::Nil)), |result| eval(result, env))
+}
+
+/// Evaluates the body with `var` bound to 0 up to one less than the count
+pub fn fn_dotimes(spec: &Object, body: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let (var, count, result) = iteration_spec(spec)?;
+    let count = Number::from_object(&eval(&count, env)?)?;
+    let loop_env = env.extend();
+    let mut i = Number::Integer(0);
+    while i.compare(&count) == Some(Ordering::Less) {
+        loop_env.define(&var, i.clone().into_object());
+        fn_eval_multiple(body, &loop_env)?;
+        i = i.add(&Number::Integer(1));
+    }
+    loop_env.define(&var, i.into_object());
+    iteration_result(result.as_ref(), &loop_env)
+}
+
+/// Evaluates the body with `var` bound to each element of a list
+pub fn fn_dolist(spec: &Object, body: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let (var, list, result) = iteration_spec(spec)?;
+    let list = eval(&list, env)?;
+    let loop_env = env.extend();
+    for item in list_items(&list)? {
+        loop_env.define(&var, item);
+        fn_eval_multiple(body, &loop_env)?;
+    }
+    loop_env.define(&var, Object::Atom(Atom::Nil));
+    iteration_result(result.as_ref(), &loop_env)
+}
+
+/// Runs `do`, or `do*` when `sequential` is set so that each init and step form sees the
+/// variables bound before it
+pub fn fn_do(variables: &Object, end: &Object, body: &[Object], env: &Environment, sequential: bool) -> Result<Object, LispError> {
+    let mut specs = Vec::new();
+    for variable in list_items(variables)? {
+        let (name, init, step) = match variable {
+            Object::Cons(_) => match variable.to_vec().as_deref() {
+                Some([name]) => (name.clone(), None, None),
+                Some([name, init]) => (name.clone(), Some(init.clone()), None),
+                Some([name, init, step]) => (name.clone(), Some(init.clone()), Some(step.clone())),
+                _ => return Err(LispError::WrongType { expected: "do variable", found: variable.clone() }),
+            },
+            _ => (variable, None, None),
+        };
+        specs.push((symbol_name(&name)?.to_string(), init, step));
+    }
+    let Some(end) = end.to_vec().filter(|list| !list.is_empty()) else {
+        return Err(LispError::WrongType { expected: "do end clause", found: end.clone() });
+    };
+    let (test, results) = (&end[0], &end[1..]);
+
+    let loop_env = env.extend();
+    let mut values = Vec::new();
+    for (name, init, _) in &specs {
+        let scope = if sequential { &loop_env } else { env };
+        let value = let_value(init.as_ref(), scope)?;
+        if sequential {
+            loop_env.define(name, value);
         } else {
-            panic!("Function object is not a list")
+            values.push((name, value));
+        }
+    }
+    for (name, value) in values {
+        loop_env.define(name, value);
+    }
+
+    while !is_true(&eval(test, &loop_env)?) {
+        fn_eval_multiple(body, &loop_env)?;
+        let mut steps = Vec::new();
+        for (name, _, step) in &specs {
+            if let Some(step) = step {
+                let value = eval(step, &loop_env)?;
+                if sequential {
+                    loop_env.define(name, value);
+                } else {
+                    steps.push((name, value));
+                }
+            }
+        }
+        for (name, value) in steps {
+            loop_env.define(name, value);
         }
-    } else {
-        panic!("Cannot apply a non-symbol")
     }
+    fn_eval_multiple(results, &loop_env)
 }
 
-pub fn fn_defun(name: &Object, formals: &Object, body: &Object, stack: &mut Stack) -> Object {
-    if let Object::Atom(Atom::Symbol(symbol)) = name {
-        stack.push(Atom::Symbol(symbol.clone()), Object::List(vec![
-            formals.clone(),
-            body.clone(),
-        ]));
-        Object::Atom(Atom::Symbol(symbol.clone()))
+/// A parsed clause of the `loop` macro
+enum LoopClause<'a> {
+    ForIn { var: &'a str, list: &'a Object },
+    ForRange { var: &'a str, from: &'a Object, to: Option<&'a Object>, inclusive: bool, by: Option<&'a Object> },
+    Collect(&'a Object),
+    Sum(&'a Object),
+    Do(&'a [Object]),
+    While(&'a Object),
+    Until(&'a Object),
+    Finally(&'a [Object]),
+}
+
+/// The state of a `for` clause while the loop runs
+enum LoopVariable {
+    List(VecDeque<Object>),
+    Range { next: Number, end: Option<Number>, inclusive: bool, step: Number },
+}
+
+fn loop_keyword(object: Option<&Object>) -> Option<&str> {
+    match object {
+        Some(Object::Atom(Atom::Symbol(symbol))) => Some(symbol),
+        _ => None,
+    }
+}
+
+const LOOP_KEYWORDS: &[&str] = &["for", "collect", "sum", "do", "while", "until", "finally"];
+
+fn parse_loop(forms: &[Object]) -> Result<Vec<LoopClause<'_>>, LispError> {
+    let mut clauses = Vec::new();
+    let mut i = 0;
+    let next = |i: &mut usize| -> Result<&Object, LispError> {
+        *i += 1;
+        forms.get(*i - 1).ok_or(LispError::WrongType { expected: "loop clause", found: Object::Atom(Atom::Nil) })
+    };
+    while i < forms.len() {
+        let keyword = next(&mut i)?;
+        match loop_keyword(Some(keyword)) {
+            Some("for") => {
+                let var = symbol_name(next(&mut i)?)?;
+                match loop_keyword(forms.get(i)) {
+                    Some("in") => {
+                        i += 1;
+                        clauses.push(LoopClause::ForIn { var, list: next(&mut i)? });
+                    }
+                    Some("from") => {
+                        i += 1;
+                        let from = next(&mut i)?;
+                        let (mut to, mut inclusive, mut by) = (None, true, None);
+                        while let Some(word @ ("to" | "below" | "by")) = loop_keyword(forms.get(i)) {
+                            i += 1;
+                            match word {
+                                "by" => by = Some(next(&mut i)?),
+                                _ => {
+                                    to = Some(next(&mut i)?);
+                                    inclusive = word == "to";
+                                }
+                            }
+                        }
+                        clauses.push(LoopClause::ForRange { var, from, to, inclusive, by });
+                    }
+                    _ => return Err(LispError::WrongType { expected: "in or from", found: forms.get(i).cloned().unwrap_or(Object::Atom(Atom::Nil)) }),
+                }
+            }
+            Some("collect") => clauses.push(LoopClause::Collect(next(&mut i)?)),
+            Some("sum") => clauses.push(LoopClause::Sum(next(&mut i)?)),
+            Some("while") => clauses.push(LoopClause::While(next(&mut i)?)),
+            Some("until") => clauses.push(LoopClause::Until(next(&mut i)?)),
+            Some(word @ ("do" | "finally")) => {
+                let start = i;
+                while i < forms.len() && !loop_keyword(forms.get(i)).is_some_and(|word| LOOP_KEYWORDS.contains(&word)) {
+                    i += 1;
+                }
+                let body = &forms[start..i];
+                clauses.push(if word == "do" { LoopClause::Do(body) } else { LoopClause::Finally(body) });
+            }
+            _ => return Err(LispError::WrongType { expected: "loop keyword", found: keyword.clone() }),
+        }
+    }
+    Ok(clauses)
+}
+
+/// Runs a subset of the `loop` macro: `for x in list`, `for x from a to b`/`below b`/`by n`,
+/// `collect`, `sum`, `do`, `while`, `until` and `finally`. A loop of plain forms repeats them
+/// forever.
+pub fn fn_loop(forms: &[Object], env: &Environment) -> Result<Object, LispError> {
+    if !forms.is_empty() && loop_keyword(forms.first()).is_none() {
+        loop {
+            fn_eval_multiple(forms, env)?;
+        }
+    }
+
+    let clauses = parse_loop(forms)?;
+    let loop_env = env.extend();
+    let mut variables = Vec::new();
+    for clause in &clauses {
+        match clause {
+            LoopClause::ForIn { list, .. } => {
+                variables.push(LoopVariable::List(list_items(&eval(list, &loop_env)?)?.into()));
+            }
+            LoopClause::ForRange { from, to, inclusive, by, .. } => {
+                let next = Number::from_object(&eval(from, &loop_env)?)?;
+                let end = to.map(|to| eval(to, &loop_env).and_then(|to| Number::from_object(&to))).transpose()?;
+                let step = by.map(|by| eval(by, &loop_env).and_then(|by| Number::from_object(&by))).transpose()?;
+                variables.push(LoopVariable::Range { next, end, inclusive: *inclusive, step: step.unwrap_or(Number::Integer(1)) });
+            }
+            _ => {}
+        }
+    }
+
+    let collects = clauses.iter().any(|clause| matches!(clause, LoopClause::Collect(_)));
+    let mut collected = Vec::new();
+    let mut sum = Number::Integer(0);
+    'iterations: loop {
+        let mut variables = variables.iter_mut();
+        for clause in &clauses {
+            match clause {
+                LoopClause::ForIn { var, .. } | LoopClause::ForRange { var, .. } => {
+                    let value = match variables.next().unwrap() {
+                        LoopVariable::List(items) => match items.pop_front() {
+                            Some(item) => item,
+                            None => break 'iterations,
+                        },
+                        LoopVariable::Range { next, end, inclusive, step } => {
+                            let past_end = end.as_ref().is_some_and(|end| match next.compare(end) {
+                                Some(Ordering::Less) => false,
+                                Some(Ordering::Equal) => !*inclusive,
+                                _ => true,
+                            });
+                            if past_end {
+                                break 'iterations;
+                            }
+                            let value = next.clone();
+                            *next = next.add(step);
+                            value.into_object()
+                        }
+                    };
+                    loop_env.define(var, value);
+                }
+                LoopClause::Collect(form) => collected.push(eval(form, &loop_env)?),
+                LoopClause::Sum(form) => sum = sum.add(&Number::from_object(&eval(form, &loop_env)?)?),
+                LoopClause::Do(body) => { fn_eval_multiple(body, &loop_env)?; }
+                LoopClause::While(test) => if !is_true(&eval(test, &loop_env)?) { break 'iterations },
+                LoopClause::Until(test) => if is_true(&eval(test, &loop_env)?) { break 'iterations },
+                LoopClause::Finally(_) => {}
+            }
+        }
+    }
+
+    for clause in &clauses {
+        if let LoopClause::Finally(body) = clause {
+            fn_eval_multiple(body, &loop_env)?;
+        }
+    }
+    if collects {
+        Ok(fn_list(&collected))
+    } else if clauses.iter().any(|clause| matches!(clause, LoopClause::Sum(_))) {
+        Ok(sum.into_object())
     } else {
-        panic!("Cannot defun a non-symbol")
+        Ok(Object::Atom(Atom::Nil))
+    }
+}
+
+/// The name of a block, where `nil` names the implicit block of the iteration forms
+fn block_name(name: &Object) -> Result<String, LispError> {
+    match name {
+        Object::Atom(Atom::Nil) => Ok(String::from("nil")),
+        _ => symbol_name(name).map(str::to_string),
+    }
+}
+
+/// Stops a `return-from` to `block` that unwound out of `result`
+pub fn catch_return(block: &Rc<Block>, result: Result<Object, LispError>) -> Result<Object, LispError> {
+    match result {
+        Err(LispError::ReturnFrom { block: target, value }) if Rc::ptr_eq(&target, block) => Ok(*value),
+        _ => result,
+    }
+}
+
+/// Opens a block around the tail of `body`, stopping a `return-from` the block while the forms
+/// before the tail run
+pub fn block_tail(block: Rc<Block>, body: Result<Tail, LispError>) -> Result<Tail, LispError> {
+    match body {
+        Ok(Tail::Call(expression, env)) => Ok(Tail::Block(block, Box::new(Tail::Call(expression, env)))),
+        Err(LispError::ReturnFrom { block: target, value }) if Rc::ptr_eq(&target, &block) => Ok(Tail::Value(*value)),
+        _ => body,
+    }
+}
+
+pub fn fn_block(name: &Object, body: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let block_env = env.extend();
+    let block = block_env.define_block(&block_name(name)?);
+    block_tail(block, eval_body(body, &block_env))
+}
+
+/// Runs an iteration form inside its implicit block named `nil`
+pub fn fn_nil_block(env: &Environment, body: impl FnOnce(&Environment) -> Result<Object, LispError>) -> Result<Object, LispError> {
+    let block_env = env.extend();
+    let block = block_env.define_block("nil");
+    catch_return(&block, body(&block_env))
+}
+
+/// Unwinds to the innermost block named `name` that the code is written in. Without one, the
+/// `return-from` unwinds to the top level and is reported there.
+pub fn fn_return_from(name: &Object, value: Option<&Object>, env: &Environment) -> Result<Object, LispError> {
+    let name = block_name(name)?;
+    let value = let_value(value, env)?;
+    let block = env.find_block(&name).unwrap_or_else(|| Rc::new(Block { name }));
+    Err(LispError::ReturnFrom { block, value: Box::new(value) })
+}
+
+pub fn fn_catch(tag: &Object, body: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let tag = eval(tag, env)?;
+    match fn_eval_multiple(body, env) {
+        Err(LispError::Throw { tag: thrown, value }) if is_true(&fn_eq(&tag, &thrown)) => Ok(*value),
+        result => result,
+    }
+}
+
+pub fn fn_throw(tag: &Object, value: &Object) -> Result<Object, LispError> {
+    Err(LispError::Throw { tag: Box::new(tag.clone()), value: Box::new(value.clone()) })
+}
+
+/// Evaluates the protected form, then the cleanup forms however the protected form exited
+pub fn fn_unwind_protect(protected: &Object, cleanup: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let result = eval(protected, env);
+    fn_eval_multiple(cleanup, env)?;
+    result
+}
+
+/// Raises a condition with `error`, `warn` or `signal`. The arguments are a message string,
+/// a condition type and optional message, or a condition object to raise again. `warn` and
+/// `signal` return nil when no `handler-case` is waiting for the condition.
+pub fn fn_signal(operator: &str, args: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let (default_kind, parent) = match operator {
+        "error" => ("simple-error", "error"),
+        "warn" => ("simple-warning", "warning"),
+        _ => ("simple-condition", "condition"),
+    };
+    let condition = match args {
+        [Object::Condition(condition)] => condition.clone(),
+        [Object::Atom(Atom::String(message))] => Rc::new(Condition::new(default_kind, parent, message)),
+        [Object::Atom(Atom::Symbol(kind))] => Rc::new(Condition::new(kind, parent, kind)),
+        [Object::Atom(Atom::Symbol(kind)), Object::Atom(Atom::String(message))] => Rc::new(Condition::new(kind, parent, message)),
+        _ => return Err(LispError::WrongType { expected: "condition designator", found: fn_list(args) }),
+    };
+    if operator == "error" || env.is_handled(&condition) {
+        return Err(LispError::Condition(condition));
+    }
+    if operator == "warn" {
+        eprintln!("Warning: {}", condition);
+    }
+    Ok(Object::Atom(Atom::Nil))
+}
+
+/// Evaluates `form`, and if it raises a condition, the body of the first clause whose type
+/// the condition belongs to, with the clause's variable bound to the condition
+pub fn fn_handler_case(form: &Object, clauses: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let mut handlers = Vec::new();
+    for clause in clauses {
+        let Some(list) = clause.to_vec().filter(|list| list.len() >= 2) else {
+            return Err(LispError::WrongType { expected: "handler clause", found: clause.clone() });
+        };
+        let (kind, variables) = (symbol_name(&list[0])?.to_string(), list_items(&list[1])?);
+        if variables.len() > 1 {
+            return Err(LispError::WrongType { expected: "handler variable list", found: fn_list(&variables) });
+        }
+        handlers.push((kind, variables.first().cloned(), list[2..].to_vec()));
+    }
+
+    let result = {
+        let _guard = env.push_handlers(handlers.iter().map(|(kind, _, _)| kind.to_string()).collect());
+        eval(form, env)
+    };
+    let Err(error) = result else { return result };
+    let Some(condition) = error.condition() else { return Err(error) };
+    for (kind, variable, body) in handlers {
+        if condition.is_a(&kind) {
+            let handler_env = env.extend();
+            if let Some(variable) = variable {
+                handler_env.define(symbol_name(&variable)?, Object::Condition(condition));
+            }
+            return fn_eval_multiple(&body, &handler_env);
+        }
+    }
+    Err(error)
+}
+
+/// Evaluates the forms, returning nil if any of them raises an error
+pub fn fn_ignore_errors(body: &[Object], env: &Environment) -> Result<Object, LispError> {
+    let result = {
+        let _guard = env.push_handlers(vec![String::from("error")]);
+        fn_eval_multiple(body, env)
+    };
+    match result {
+        Err(error) if error.condition().is_some_and(|condition| condition.is_a("error")) => Ok(Object::Atom(Atom::Nil)),
+        result => result,
+    }
+}
+
+fn as_condition(object: &Object) -> Result<&Condition, LispError> {
+    match object {
+        Object::Condition(condition) => Ok(condition),
+        _ => Err(LispError::WrongType { expected: "condition", found: object.clone() }),
+    }
+}
+
+pub fn fn_condition_message(condition: &Object) -> Result<Object, LispError> {
+    Ok(Object::Atom(Atom::String(as_condition(condition)?.message.clone())))
+}
+
+pub fn fn_condition_type(condition: &Object) -> Result<Object, LispError> {
+    Ok(Object::Atom(Atom::Symbol(as_condition(condition)?.kind().to_string())))
+}
+
+/// Collects garbage cycles, returning how many objects were freed
+pub fn fn_gc() -> Object {
+    Number::from(gc::collect()).into_object()
+}
+
+/// Prints the number and size of live objects of each kind, returning the total bytes
+pub fn fn_room() -> Object {
+    let usage = gc::room();
+    fresh_line();
+    for usage in &usage {
+        write_output(&format!("{}: {} objects, {} bytes\n", usage.kind, usage.count, usage.bytes));
+    }
+    let count = usage.iter().map(|usage| usage.count).sum::<usize>();
+    let bytes = usage.iter().map(|usage| usage.bytes).sum::<usize>();
+    write_output(&format!("total: {} objects, {} bytes\n", count, bytes));
+    Number::from(bytes).into_object()
+}
+
+/// The argument of a `(name x)` form, e.g. the `x` in `(unquote x)`
+fn special_argument(object: &Object, name: &str) -> Option<Object> {
+    let Object::Cons(cell) = object else { return None };
+    match (cell.car(), cell.cdr()) {
+        (Object::Atom(Atom::Symbol(symbol)), Object::Cons(rest)) if symbol == name && rest.cdr() == Object::Atom(Atom::Nil) => {
+            Some(rest.car())
+        }
+        _ => None,
+    }
+}
+
+/// Builds the structure of a backquoted template, evaluating `unquote`d forms and splicing
+/// in `unquote-splicing`d lists. `depth` counts the enclosing quasiquotes beyond the first.
+fn quasiquote(template: &Object, depth: usize, env: &Environment) -> Result<Object, LispError> {
+    if let Some(form) = special_argument(template, "unquote") {
+        return match depth {
+            0 => eval(&form, env),
+            _ => Ok(fn_list(&[Object::Atom(Atom::Symbol(String::from("unquote"))), quasiquote(&form, depth - 1, env)?])),
+        };
+    }
+    if let Some(form) = special_argument(template, "quasiquote") {
+        return Ok(fn_list(&[Object::Atom(Atom::Symbol(String::from("quasiquote"))), quasiquote(&form, depth + 1, env)?]));
+    }
+    if special_argument(template, "unquote-splicing").is_some() && depth == 0 {
+        return Err(LispError::WrongType { expected: "list element for unquote-splicing", found: template.clone() });
+    }
+
+    if !matches!(template, Object::Cons(_)) {
+        return Ok(template.clone());
     }
+    let mut items = Vec::new();
+    let mut rest = template.clone();
+    let mut first = true;
+    let tail = loop {
+        let Object::Cons(cell) = &rest else {
+            break quasiquote(&rest, depth, env)?;
+        };
+        // `(a . ,b)` reads as `(a unquote b)`, so a special form in the cdr is a dotted tail
+        let special = ["unquote", "unquote-splicing", "quasiquote"].iter().any(|name| special_argument(&rest, name).is_some());
+        if special && !first {
+            break quasiquote(&rest, depth, env)?;
+        }
+        let item = cell.car();
+        match special_argument(&item, "unquote-splicing") {
+            Some(form) if depth == 0 => items.extend(list_items(&eval(&form, env)?)?),
+            Some(form) => {
+                let inner = quasiquote(&form, depth - 1, env)?;
+                items.push(fn_list(&[Object::Atom(Atom::Symbol(String::from("unquote-splicing"))), inner]));
+            }
+            None => items.push(quasiquote(&item, depth, env)?),
+        }
+        let next = cell.cdr();
+        rest = next;
+        first = false;
+    };
+    Ok(Object::dotted_list(items, tail))
 }
 
-pub fn fn_setq(name: &Object, value: &Object, stack: &mut Stack) -> Object {
+pub fn fn_quasiquote(template: &Object, env: &Environment) -> Result<Object, LispError> {
+    quasiquote(template, 0, env)
+}
+
+pub fn fn_setq(name: &Object, value: &Object, env: &Environment) -> Result<Object, LispError> {
     if let Object::Atom(Atom::Symbol(symbol)) = name {
-        stack.push(Atom::Symbol(symbol.clone()), value.clone());
-        value.clone()
+        env.set(symbol, value.clone());
+        Ok(value.clone())
     } else {
-        panic!("Cannot setq to a non-symbol")
+        Err(LispError::WrongType { expected: "symbol", found: name.clone() })
     }
 }
 
 pub fn fn_listp(object: &Object) -> Object {
     match object {
-        Object::List(_) => Object::Atom(Atom::T),
-        Object::ConsCell(_) => Object::Atom(Atom::T),
+        Object::Cons(_) | Object::Atom(Atom::Nil) => Object::Atom(Atom::T),
         _ => Object::Atom(Atom::Nil)
     }
 }
@@ -320,109 +1129,314 @@ pub fn fn_listp(object: &Object) -> Object {
 pub fn fn_atom(object: &Object) -> Object {
     match object {
         Object::Atom(_) => Object::Atom(Atom::T),
+        Object::Function(_) | Object::Condition(_) => Object::Atom(Atom::T),
         _ => Object::Atom(Atom::Nil)
     }
 }
 
 pub fn fn_null(object: &Object) -> Object {
+    bool_obj(*object == Object::Atom(Atom::Nil))
+}
+
+pub fn fn_car(object: &Object) -> Result<Object, LispError> {
     match object {
-        Object::List(list) => {
-            if list.len() == 0 {
-                Object::Atom(Atom::T)
-            } else {
-                Object::Atom(Atom::Nil)
-            }
-        }
-        Object::Atom(atom) => {
-            match atom {
-                Atom::Nil => { Object::Atom(Atom::T) }
-                _ => { Object::Atom(Atom::Nil) }
-            }
-        }
-        Object::ConsCell(_) => Object::Atom(Atom::Nil)
+        Object::Cons(cell) => { Ok(cell.car()) }
+        Object::Atom(Atom::Nil) => { Ok(Object::Atom(Atom::Nil)) }
+        _ => Err(LispError::WrongType { expected: "list", found: object.clone() })
     }
 }
 
-pub fn fn_car(object: &Object) -> Object {
+pub fn fn_cdr(object: &Object) -> Result<Object, LispError> {
     match object {
-        Object::List(list) => {
-            if list.len() == 0 {
-                Object::Atom(Atom::Nil)
-            } else {
-                list[0].clone()
-            }
-        }
-        Object::ConsCell(cell) => { cell.car.clone() }
-        Object::Atom(Atom::Nil) => { Object::Atom(Atom::Nil) }
-        Object::Atom(_) => panic!("Cannot call CAR on an atom")
+        Object::Cons(cell) => { Ok(cell.cdr()) }
+        Object::Atom(Atom::Nil) => { Ok(Object::Atom(Atom::Nil)) }
+        _ => Err(LispError::WrongType { expected: "list", found: object.clone() })
     }
 }
 
-pub fn fn_cdr(object: &Object) -> Object {
+fn as_cons(object: &Object) -> Result<&Rc<ConsCell>, LispError> {
     match object {
-        Object::List(list) => {
-            if list.len() == 0 {
-                Object::Atom(Atom::Nil)
+        Object::Cons(cell) => Ok(cell),
+        _ => Err(LispError::WrongType { expected: "cons", found: object.clone() }),
+    }
+}
+
+/// Replaces the car of a cons in place, returning the cons
+pub fn fn_rplaca(cons: &Object, car: &Object) -> Result<Object, LispError> {
+    as_cons(cons)?.set_car(car.clone());
+    Ok(cons.clone())
+}
+
+/// Replaces the cdr of a cons in place, returning the cons
+pub fn fn_rplacd(cons: &Object, cdr: &Object) -> Result<Object, LispError> {
+    as_cons(cons)?.set_cdr(cdr.clone());
+    Ok(cons.clone())
+}
+
+/// Joins lists by pointing the last cdr of each non-empty list at the next argument. As in
+/// Common Lisp the last argument can be any object, which becomes the tail of the result.
+pub fn fn_nconc(lists: &[Object]) -> Result<Object, LispError> {
+    let Some((tail, lists)) = lists.split_last() else {
+        return Ok(Object::Atom(Atom::Nil));
+    };
+    // Check every argument before changing any of them
+    let mut cells = Vec::new();
+    for list in lists.iter().filter(|list| **list != Object::Atom(Atom::Nil)) {
+        let cell = as_cons(list)?;
+        let last = ConsCell::last(cell).ok_or_else(|| LispError::WrongType { expected: "proper list", found: list.clone() })?;
+        cells.push((cell, last));
+    }
+    let mut result = tail.clone();
+    for (cell, last) in cells.into_iter().rev() {
+        last.set_cdr(result);
+        result = Object::Cons(Rc::clone(cell));
+    }
+    Ok(result)
+}
+
+/// Stores a value in a place: a variable, or the car or cdr of a cons
+pub fn fn_setf(place: &Object, value: &Object, env: &Environment) -> Result<Object, LispError> {
+    if let Object::Atom(Atom::Symbol(_)) = place {
+        return fn_setq(place, &eval(value, env)?, env);
+    }
+    match place.to_vec().as_deref() {
+        Some([Object::Atom(Atom::Symbol(accessor)), cons]) if accessor == "car" || accessor == "cdr" => {
+            let cons = eval(cons, env)?;
+            let value = eval(value, env)?;
+            if accessor == "car" {
+                fn_rplaca(&cons, &value)?;
             } else {
-                Object::List(list[1..].to_vec())
+                fn_rplacd(&cons, &value)?;
             }
+            Ok(value)
         }
-        Object::Atom(Atom::Nil) => { Object::Atom(Atom::Nil) }
-        Object::ConsCell(cell) => { cell.cdr.clone() }
-        _ => panic!("Invalid CDR")
+        _ => Err(LispError::WrongType { expected: "place", found: place.clone() }),
     }
 }
 
+pub fn fn_list(args: &[Object]) -> Object {
+    Object::list(args.to_vec())
+}
+
 pub fn fn_cons(car: &Object, cdr: &Object) -> Object {
-    match cdr {
-        Object::List(list) => {
-            let mut new_list = vec![car.clone()];
-            new_list.extend(list.iter().cloned());
-            Object::List(new_list)
+    Object::cons(car.clone(), cdr.clone())
+}
+
+thread_local! {
+    /// Whether everything written to standard output so far ends with a newline
+    static AT_LINE_START: Cell<bool> = const { Cell::new(true) };
+    /// Forms read from standard input that `read` hasn't returned yet
+    static PENDING_INPUT: RefCell<VecDeque<Object>> = const { RefCell::new(VecDeque::new()) };
+}
+
+fn write_output(text: &str) {
+    print!("{}", text);
+    let _ = std::io::stdout().flush();
+    if let Some(last) = text.chars().last() {
+        AT_LINE_START.set(last == '\n');
+    }
+}
+
+/// Starts a new line unless the output is already at the start of one
+pub fn fresh_line() {
+    if !AT_LINE_START.get() {
+        write_output("\n");
+    }
+}
+
+/// Writes an object so that `read` can read it back
+pub fn fn_prin1(object: &Object) -> Object {
+    write_output(&fn_print_helper(object));
+    object.clone()
+}
+
+/// Writes an object for people to read, without quoting strings
+pub fn fn_princ(object: &Object) -> Object {
+    write_output(&princ_to_string(object));
+    object.clone()
+}
+
+/// Writes a newline followed by the object as `prin1` does
+pub fn fn_print(object: &Object) -> Object {
+    write_output("\n");
+    fn_prin1(object)
+}
+
+/// Writes a newline followed by the object laid out over indented lines that fit within the
+/// right margin
+pub fn fn_pprint(object: &Object, env: &Environment) -> Object {
+    write_output(&format!("\n{}", pretty_print(object, right_margin(env))));
+    Object::Atom(Atom::Nil)
+}
+
+/// Reads the next form from standard input, reading more lines until it is complete
+pub fn fn_read() -> Result<Object, LispError> {
+    if let Some(form) = PENDING_INPUT.with_borrow_mut(VecDeque::pop_front) {
+        return Ok(form);
+    }
+    let mut input = String::new();
+    loop {
+        let read = std::io::stdin().read_line(&mut input).map_err(|_| LispError::EndOfFile)?;
+        match parse_all(&input) {
+            Ok(forms) if forms.is_empty() && read > 0 => continue,
+            Ok(forms) => {
+                let mut forms = VecDeque::from(forms);
+                let form = forms.pop_front().ok_or(LispError::EndOfFile)?;
+                PENDING_INPUT.with_borrow_mut(|pending| pending.extend(forms));
+                return Ok(form);
+            }
+            Err(ParseError::UnexpectedEof(_)) if read > 0 => continue,
+            Err(error) => return Err(error.into()),
         }
-        Object::Atom(Atom::Nil) => {
-            Object::List(vec![car.clone()])
+    }
+}
+
+/// Reads the first form in a string
+pub fn fn_read_from_string(string: &Object) -> Result<Object, LispError> {
+    let Object::Atom(Atom::String(source)) = string else {
+        return Err(LispError::WrongType { expected: "string", found: string.clone() });
+    };
+    parse_all(source)?.into_iter().next().ok_or(LispError::EndOfFile)
+}
+
+fn escape_string(val: &str) -> String {
+    let mut str = String::from('"');
+    for c in val.chars() {
+        match c {
+            '"' => str.push_str("\\\""),
+            '\\' => str.push_str("\\\\"),
+            '\n' => str.push_str("\\n"),
+            '\t' => str.push_str("\\t"),
+            '\r' => str.push_str("\\r"),
+            c if c.is_control() => str.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => str.push(c),
         }
-        _ => Object::ConsCell(Box::new(ConsCell::new(car.clone(), cdr.clone())))
     }
+    str.push('"');
+    str
 }
 
-pub fn fn_print(object: &Object) -> Object {
-    let str = fn_print_helper(object);
-    println!("{}", str);
-    Object::Atom(Atom::String(str))
+/// Prints an object so that it can be read back in, quoting and escaping strings
+pub fn fn_print_helper(object: &Object) -> String {
+    write_object(object, true)
+}
+
+/// Prints an object for people to read: strings appear without quotes or escapes and
+/// conditions as their message
+pub fn princ_to_string(object: &Object) -> String {
+    write_object(object, false)
 }
 
-fn fn_print_helper(object: &Object) -> String {
+fn write_object(object: &Object, escape: bool) -> String {
+    let mut writer = ObjectWriter { escape, circular: circular_conses(object), labels: HashMap::new() };
     let mut str = String::new();
-    match object {
-        Object::Atom(atom) => {
-            match atom {
-                Atom::Integer(number) => str.push_str(&number.to_string()),
-                Atom::Float(number) => str.push_str(&number.to_string()),
-                Atom::Symbol(name) => str.push_str(name),
-                Atom::String(val) => str.push_str(val),
-                Atom::T => str.push('T'),
-                Atom::Nil => str.push_str("NIL")
-            }
+    writer.write(object, &mut str);
+    str
+}
+
+/// The addresses of the conses that `object` refers back to from inside themselves
+pub fn circular_conses(object: &Object) -> HashSet<usize> {
+    let mut circular = HashSet::new();
+    if let Object::Cons(_) = object {
+        find_circular(object, &mut HashSet::new(), &mut circular);
+    }
+    circular
+}
+
+/// Adds to `circular` the conses that `object` refers back to from inside themselves. Those
+/// are written with a `#n=` label where they start and as `#n#` where they recur, so that
+/// printing a circular list ends. `active` holds the conses being written around `object`.
+fn find_circular(object: &Object, active: &mut HashSet<usize>, circular: &mut HashSet<usize>) {
+    let mut chain = Vec::new();
+    let mut rest = object.clone();
+    while let Object::Cons(cell) = rest {
+        let address = gc::address(&cell);
+        if !active.insert(address) {
+            circular.insert(address);
+            break;
         }
-        Object::List(list) => {
-            str.push('(');
-            for (i, item) in list.iter().enumerate() {
-                str.push_str(&fn_print_helper(item));
-                if i < list.len() - 1 {
-                    str.push(' ');
+        chain.push(address);
+        find_circular(&cell.car(), active, circular);
+        rest = cell.cdr();
+    }
+    for address in chain {
+        active.remove(&address);
+    }
+}
+
+struct ObjectWriter {
+    escape: bool,
+    circular: HashSet<usize>,
+    /// The labels given so far to circular conses
+    labels: HashMap<usize, usize>,
+}
+
+impl ObjectWriter {
+    fn write(&mut self, object: &Object, str: &mut String) {
+        match object {
+            Object::Atom(atom) => {
+                match atom {
+                    Atom::Integer(number) => str.push_str(&number.to_string()),
+                    Atom::BigInt(number) => str.push_str(&number.to_string()),
+                    Atom::Ratio(ratio) => str.push_str(&ratio.to_string()),
+                    Atom::Float(number) => str.push_str(&format_float(number.0)),
+                    Atom::Symbol(name) => str.push_str(name),
+                    Atom::String(val) if self.escape => str.push_str(&escape_string(val)),
+                    Atom::String(val) => str.push_str(val),
+                    Atom::T => str.push('T'),
+                    Atom::Nil => str.push_str("NIL")
                 }
             }
-            str.push(')');
+            Object::Cons(cell) => {
+                if self.write_label(cell, str) {
+                    return;
+                }
+                str.push('(');
+                self.write(&cell.car(), str);
+                let mut rest = cell.cdr();
+                loop {
+                    match rest {
+                        // A circular cons is written after a dot so its label stands for it
+                        Object::Cons(cell) if !self.circular.contains(&gc::address(&cell)) => {
+                            str.push(' ');
+                            self.write(&cell.car(), str);
+                            rest = cell.cdr();
+                        }
+                        _ => break,
+                    }
+                }
+                if rest != Object::Atom(Atom::Nil) {
+                    str.push_str(" . ");
+                    self.write(&rest, str);
+                }
+                str.push(')');
+            }
+            Object::Function(function) => {
+                str.push_str(if matches!(function.as_ref(), Function::Macro(_)) { "#<macro " } else { "#<function " });
+                str.push_str(function.name().unwrap_or("lambda"));
+                str.push('>');
+            }
+            Object::Condition(condition) if self.escape => {
+                str.push_str(&format!("#<{} {}>", condition.kind(), escape_string(&condition.message)));
+            }
+            Object::Condition(condition) => str.push_str(&condition.message),
+        }
+    }
+
+    /// Writes the label of a circular cons: `#n=` the first time, after which the cons is
+    /// written out, and `#n#` after that. Returns whether the cons was already written.
+    fn write_label(&mut self, cell: &Rc<ConsCell>, str: &mut String) -> bool {
+        let address = gc::address(cell);
+        if !self.circular.contains(&address) {
+            return false;
         }
-        Object::ConsCell(cell) => {
-            str.push('(');
-            str.push_str(&fn_print_helper(&cell.car));
-            str.push('.');
-            str.push_str(&fn_print_helper(&cell.cdr));
-            str.push(')');
+        if let Some(label) = self.labels.get(&address) {
+            str.push_str(&format!("#{}#", label));
+            return true;
         }
+        let label = self.labels.len() + 1;
+        self.labels.insert(address, label);
+        str.push_str(&format!("#{}=", label));
+        false
     }
-    str
-}
\ No newline at end of file
+}
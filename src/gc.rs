@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::parser::Object;
+
+/// How many objects may be allocated before the first automatic collection
+const MIN_THRESHOLD: usize = 100_000;
+
+/// An object on the heap that can hold references to other heap objects, and so can be
+/// part of a reference cycle
+pub trait Trace {
+    /// Calls `visit` with the address of each heap object this one refers to
+    fn trace(&self, visit: &mut dyn FnMut(usize));
+    /// Drops this object's references so that a cycle of garbage it belongs to is freed
+    fn clear(&self);
+    /// The name this kind of object is listed under by `room`
+    fn kind(&self) -> &'static str;
+}
+
+/// Every tracked object, held weakly so that reference counting still frees what it can
+struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    allocated: usize,
+    threshold: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap { objects: Vec::new(), allocated: 0, threshold: MIN_THRESHOLD });
+}
+
+/// The number and approximate size in bytes of the live objects of one kind
+#[derive(Debug, PartialEq)]
+pub struct Usage {
+    pub kind: &'static str,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Identifies a heap object in `Trace::trace`
+pub fn address<T: ?Sized>(object: &Rc<T>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+/// Registers a newly allocated object with the collector
+pub fn track<T: Trace + 'static>(object: &Rc<T>) {
+    let object: Weak<T> = Rc::downgrade(object);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(object);
+        heap.allocated += 1;
+    });
+}
+
+/// Visits the heap object an object value refers to, if any
+pub fn trace_object(object: &Object, visit: &mut dyn FnMut(usize)) {
+    match object {
+        Object::Cons(cell) => visit(address(cell)),
+        Object::Function(function) => visit(address(function)),
+        Object::Atom(_) | Object::Condition(_) => {}
+    }
+}
+
+/// Collects garbage once enough objects have been allocated since the last collection
+pub fn maybe_collect() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.allocated >= heap.threshold
+    });
+    if due {
+        collect();
+    }
+}
+
+/// Frees the cycles of objects that nothing outside the heap can reach, returning how many
+/// objects were freed.
+///
+/// Reference counting already frees everything else, so this looks for objects kept alive
+/// only by each other. Subtracting the references tracked objects hold to one another from
+/// their reference counts leaves the references from outside the heap: the environments and
+/// values held by the evaluator. Objects with any are roots, and whatever the roots can't
+/// reach is garbage.
+pub fn collect() -> usize {
+    let objects = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.objects.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+    });
+    let index = objects.iter().enumerate().map(|(i, object)| (address(object), i)).collect::<HashMap<_, _>>();
+
+    // Each count includes the reference held by `objects`
+    let mut external = objects.iter().map(|object| Rc::strong_count(object) - 1).collect::<Vec<_>>();
+    for object in &objects {
+        object.trace(&mut |child| {
+            if let Some(&i) = index.get(&child) {
+                external[i] = external[i].saturating_sub(1);
+            }
+        });
+    }
+
+    let mut reachable = external.iter().map(|&count| count > 0).collect::<Vec<_>>();
+    let mut pending = (0..objects.len()).filter(|&i| reachable[i]).collect::<Vec<_>>();
+    while let Some(i) = pending.pop() {
+        objects[i].trace(&mut |child| {
+            if let Some(&j) = index.get(&child) {
+                if !reachable[j] {
+                    reachable[j] = true;
+                    pending.push(j);
+                }
+            }
+        });
+    }
+
+    let garbage = objects.iter().zip(&reachable).filter(|(_, reachable)| !**reachable).map(|(object, _)| object).collect::<Vec<_>>();
+    for object in &garbage {
+        object.clear();
+    }
+    let freed = garbage.len();
+    let live = objects.len() - freed;
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.allocated = 0;
+        heap.threshold = live.max(MIN_THRESHOLD);
+    });
+    freed
+}
+
+/// The live objects of each kind, including garbage not yet collected
+pub fn room() -> Vec<Usage> {
+    let mut usage: Vec<Usage> = Vec::new();
+    HEAP.with(|heap| {
+        for object in heap.borrow().objects.iter().filter_map(Weak::upgrade) {
+            let bytes = std::mem::size_of_val(object.as_ref()) + 2 * std::mem::size_of::<usize>();
+            match usage.iter_mut().find(|usage| usage.kind == object.kind()) {
+                Some(usage) => {
+                    usage.count += 1;
+                    usage.bytes += bytes;
+                }
+                None => usage.push(Usage { kind: object.kind(), count: 1, bytes }),
+            }
+        }
+    });
+    usage.sort_by_key(|usage| usage.kind);
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{eval, Environment};
+    use crate::parser::parse_all;
+    use crate::tokenizer::Atom;
+
+    fn live(kind: &str) -> usize {
+        room().iter().find(|usage| usage.kind == kind).map_or(0, |usage| usage.count)
+    }
+
+    fn live_counts() -> (usize, usize, usize) {
+        (live("cons"), live("environment"), live("function"))
+    }
+
+    #[test]
+    fn collect_cycles() {
+        let env = Environment::new();
+        let run = |source: &str| eval(&parse_all(source).unwrap().remove(0), &env).unwrap();
+        collect();
+        let before = live_counts();
+
+        run("(let ((x (list 1 2))) (setf (cdr (cdr x)) x) nil)");
+        assert_eq!(live("cons"), before.0 + 2);
+        run("(labels ((f () 1)) (f))");
+        assert!(live_counts() != before);
+        assert!(collect() > 0);
+        assert_eq!(live_counts(), before);
+
+        // Cycles still reachable from the environment are kept
+        run("(setq a (list 1 2))");
+        run("(setf (cdr (cdr a)) a)");
+        run("(defun make-counter () (let ((n 0)) (lambda () (setq n (+ n 1)))))");
+        run("(setq counter (make-counter))");
+        assert_eq!(run("(gc)"), Object::Atom(Atom::Integer(0)));
+        assert_eq!(run("(car (cdr (cdr a)))"), Object::Atom(Atom::Integer(1)));
+        assert_eq!(run("(funcall counter)"), Object::Atom(Atom::Integer(1)));
+        assert!(matches!(run("(room)"), Object::Atom(Atom::Integer(bytes)) if bytes > 0));
+    }
+}
@@ -1,109 +1,583 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use crate::error::{Condition, LispError};
 use crate::functions::*;
-use crate::parser::Object;
+use crate::gc::{self, Trace};
+use crate::parser::{list_span, symbol_span, Object};
 use crate::tokenizer::Atom;
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Stack {
-    stack: Vec<(Atom, Object)>,
+/// Names of the builtin functions handled by `call_function`
+pub const BUILTINS: &[&str] = &[
+    "null", "not", "car", "cdr", "cons", "list", "rplaca", "rplacd", "nconc",
+    "print", "prin1", "princ", "pprint", "read", "read-from-string", "atom", "listp", "eq", "eval", "equal",
+    "+", "-", "*", "/", "mod", "floor", "apply", "funcall", "load", "throw", "<=", ">=", ">", "<",
+    "error", "warn", "signal", "condition-message", "condition-type",
+    "macroexpand", "macroexpand-1", "gc", "room",
+];
+
+/// Names of the special forms handled by `eval_special_form`
+pub const SPECIAL_FORMS: &[&str] = &[
+    "quote", "quasiquote", "setq", "setf", "defun", "defmacro", "lambda", "function", "cond", "and",
+    "let", "let*", "flet", "labels", "or", "if", "when", "unless", "progn", "prog1", "prog2",
+    "dotimes", "dolist", "do", "do*", "loop",
+    "block", "return-from", "return", "catch", "unwind-protect", "handler-case", "ignore-errors",
+];
+
+/// One entry into a `block`, which `return-from` unwinds to. Blocks compare by identity, so
+/// entering the same block again, as a recursive call does, makes a distinct target.
+#[derive(Debug)]
+pub struct Block {
+    pub name: String,
+}
+
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// A single scope of variable and function bindings, along with the blocks its code can
+/// return from
+#[derive(Default)]
+struct Frame {
+    variables: RefCell<HashMap<String, Object>>,
+    functions: RefCell<HashMap<String, Object>>,
+    blocks: RefCell<Vec<Rc<Block>>>,
+    parent: Option<Rc<Frame>>,
+}
+
+impl Trace for Frame {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        for object in self.variables.borrow().values().chain(self.functions.borrow().values()) {
+            gc::trace_object(object, visit);
+        }
+        if let Some(parent) = &self.parent {
+            visit(gc::address(parent));
+        }
+    }
+
+    fn clear(&self) {
+        let variables = self.variables.take();
+        let functions = self.functions.take();
+        drop((variables, functions));
+    }
+
+    fn kind(&self) -> &'static str {
+        "environment"
+    }
+}
+
+/// Native stack each level of evaluation may use, enough for the deepest frames an unoptimized
+/// build uses per level
+pub const STACK_PER_DEPTH: usize = 16 << 10;
+
+/// The limit `Environment::new` sets on how deeply `eval` may nest. It leaves a quarter of the
+/// 2 MiB stack Rust gives spawned threads to the host.
+pub const DEFAULT_MAX_DEPTH: usize = 96;
+
+/// Evaluation state shared by every environment created from the same global environment
+struct EvalState {
+    depth: Cell<usize>,
+    max_depth: Cell<usize>,
+    /// The condition types handled by each active `handler-case`, innermost last
+    handlers: RefCell<Vec<Vec<String>>>,
+}
+
+/// Leaves one level of evaluation depth when dropped
+struct DepthGuard<'a> {
+    state: &'a EvalState,
+}
+
+impl Drop for DepthGuard<'_> {
+    fn drop(&mut self) {
+        self.state.depth.set(self.state.depth.get() - 1);
+    }
+}
+
+/// Removes the innermost set of handlers when dropped
+pub struct HandlerGuard<'a> {
+    state: &'a EvalState,
+}
+
+impl Drop for HandlerGuard<'_> {
+    fn drop(&mut self) {
+        self.state.handlers.borrow_mut().pop();
+    }
+}
+
+/// A chain of frames ending in the global frame. Cloning shares the frames.
+#[derive(Clone)]
+pub struct Environment {
+    frame: Rc<Frame>,
+    state: Rc<EvalState>,
+}
+
+impl Environment {
+    /// Creates a global environment limited to `DEFAULT_MAX_DEPTH` levels of evaluation, which
+    /// fits on any thread's default stack. Use `set_max_depth` to allow deeper recursion on a
+    /// thread with a bigger stack.
+    pub fn new() -> Environment {
+        let frame = Rc::new(Frame::default());
+        gc::track(&frame);
+        Environment {
+            frame,
+            state: Rc::new(EvalState {
+                depth: Cell::new(0),
+                max_depth: Cell::new(DEFAULT_MAX_DEPTH),
+                handlers: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Creates a new empty frame whose parent is this environment
+    pub fn extend(&self) -> Environment {
+        let frame = Rc::new(Frame { parent: Some(self.frame.clone()), ..Frame::default() });
+        gc::track(&frame);
+        Environment {
+            frame,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Sets how deeply evaluation may nest before failing with `StackDepthExceeded`. The limit
+    /// only stops deep recursion before the native stack overflows if the evaluating thread has
+    /// at least `max_depth * STACK_PER_DEPTH` bytes of stack beyond what its caller uses.
+    pub fn set_max_depth(&self, max_depth: usize) {
+        self.state.max_depth.set(max_depth);
+    }
+
+    /// Marks conditions of the given types as handled until the guard is dropped
+    pub fn push_handlers(&self, types: Vec<String>) -> HandlerGuard<'_> {
+        self.state.handlers.borrow_mut().push(types);
+        HandlerGuard { state: &self.state }
+    }
+
+    /// Whether an active `handler-case` will handle the condition
+    pub fn is_handled(&self, condition: &Condition) -> bool {
+        self.state.handlers.borrow().iter().flatten().any(|kind| condition.is_a(kind))
+    }
+
+    /// Enters one level of evaluation, failing if that would exceed the depth limit
+    fn enter(&self) -> Result<DepthGuard<'_>, LispError> {
+        let depth = self.state.depth.get();
+        let max_depth = self.state.max_depth.get();
+        if depth >= max_depth {
+            return Err(LispError::StackDepthExceeded(max_depth));
+        }
+        self.state.depth.set(depth + 1);
+        Ok(DepthGuard { state: &self.state })
+    }
+
+    fn frames(&self) -> impl Iterator<Item = &Frame> {
+        std::iter::successors(Some(self.frame.as_ref()), |frame| frame.parent.as_deref())
+    }
+
+    fn global(&self) -> &Frame {
+        self.frames().last().unwrap()
+    }
+
+    /// Binds `name` in the innermost frame
+    pub fn define(&self, name: &str, object: Object) {
+        self.frame.variables.borrow_mut().insert(name.to_string(), object);
+    }
+
+    pub fn find(&self, name: &str) -> Option<Object> {
+        self.frames().find_map(|frame| frame.variables.borrow().get(name).cloned())
+    }
+
+    /// Updates the nearest existing binding of `name`, otherwise sets the global value
+    pub fn set(&self, name: &str, object: Object) {
+        let frame = self.frames()
+            .find(|frame| frame.variables.borrow().contains_key(name))
+            .unwrap_or_else(|| self.global());
+        frame.variables.borrow_mut().insert(name.to_string(), object);
+    }
+
+    pub fn define_function(&self, name: &str, function: Object) {
+        self.global().functions.borrow_mut().insert(name.to_string(), function);
+    }
+
+    /// Binds a function in the innermost frame, as `flet` and `labels` do
+    pub fn define_local_function(&self, name: &str, function: Object) {
+        self.frame.functions.borrow_mut().insert(name.to_string(), function);
+    }
+
+    pub fn find_function(&self, name: &str) -> Option<Object> {
+        self.frames().find_map(|frame| frame.functions.borrow().get(name).cloned())
+    }
+
+    /// Enters a block named `name` that the code evaluated in this environment can return from
+    pub fn define_block(&self, name: &str) -> Rc<Block> {
+        let block = Rc::new(Block { name: name.to_string() });
+        self.frame.blocks.borrow_mut().push(block.clone());
+        block
+    }
+
+    /// The innermost block named `name` that encloses this environment's code
+    pub fn find_block(&self, name: &str) -> Option<Rc<Block>> {
+        self.frames().find_map(|frame| frame.blocks.borrow().iter().rev().find(|block| block.name == name).cloned())
+    }
+
+    /// Visits the innermost frame, which refers to the rest of the chain
+    pub fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        visit(gc::address(&self.frame));
+    }
+
+    /// Every variable and function name visible from this environment, possibly repeated
+    pub fn symbols(&self) -> Vec<String> {
+        self.frames()
+            .flat_map(|frame| {
+                let variables = frame.variables.borrow().keys().cloned().collect::<Vec<_>>();
+                let functions = frame.functions.borrow().keys().cloned().collect::<Vec<_>>();
+                variables.into_iter().chain(functions)
+            })
+            .collect()
+    }
+}
+
+impl PartialEq for Environment {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.frame, &other.frame)
+    }
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Environment({} frames)", self.frames().count())
+    }
+}
+
+/// The result of evaluating one step of an expression: either its value, or an expression in
+/// tail position that produces the value. Tail calls are evaluated by the loop in `eval` so
+/// that they don't grow the Rust stack.
+pub enum Tail {
+    Value(Object),
+    Call(Object, Environment),
+    /// A tail evaluated inside a block, so that `return-from` it still works
+    Block(Rc<Block>, Box<Tail>),
 }
 
-impl Stack {
-    pub fn new() -> Stack {
-        Stack { stack: vec![] }
+impl Tail {
+    pub fn into_value(self) -> Result<Object, LispError> {
+        match self {
+            Tail::Value(value) => Ok(value),
+            Tail::Call(expression, env) => eval(&expression, &env),
+            Tail::Block(block, tail) => catch_return(&block, tail.into_value()),
+        }
     }
+}
 
-    pub fn push(&mut self, atom: Atom, object: Object) {
-        self.stack.push((atom, object));
+pub fn eval(expression: &Object, env: &Environment) -> Result<Object, LispError> {
+    let _guard = env.enter()?;
+    gc::maybe_collect();
+    let mut tail = eval_step(expression, env).map_err(|error| locate(error, expression));
+    // The last list evaluated, which holds any atom evaluated after it as a tail call
+    let mut source = expression.clone();
+    // The blocks opened by tail calls so far. They all end with the last tail call, so a
+    // `return-from` any of them returns from this whole evaluation. They are held weakly, and
+    // those nothing can return from any more are dropped, so a long tail recursion stays small.
+    let mut blocks: Vec<Weak<Block>> = Vec::new();
+    let mut prune_at = 16;
+    loop {
+        match tail {
+            Ok(Tail::Value(value)) => return Ok(value),
+            Ok(Tail::Call(expression, env)) => {
+                if let Object::Cons(_) = expression {
+                    source = expression.clone();
+                }
+                tail = eval_step(&expression, &env).map_err(|error| locate(error, &source));
+            }
+            Ok(Tail::Block(block, inner)) => {
+                if blocks.len() >= prune_at {
+                    blocks.retain(|block| block.strong_count() > 0);
+                    prune_at = (2 * blocks.len()).max(16);
+                }
+                blocks.push(Rc::downgrade(&block));
+                tail = Ok(*inner);
+            }
+            Err(LispError::ReturnFrom { block, value }) if blocks.iter().any(|open| open.as_ptr() == Rc::as_ptr(&block)) => {
+                return Ok(*value);
+            }
+            Err(error) => return Err(error),
+        }
     }
+}
 
-    pub fn find(&self, atom: &Atom) -> Option<Object> {
-        self.stack.iter().rev().find(|(s, _)| s == atom).map(|(_, o)| o.clone())
+/// Records where an error from evaluating `form` happened: at the variable for an unbound one
+/// written in `form`, otherwise at `form`. Only code read by `parse_source` has positions, and
+/// an error that already has one keeps it.
+pub fn locate(error: LispError, form: &Object) -> LispError {
+    let Object::Cons(cell) = form else { return error };
+    let span = match &error {
+        LispError::Located { .. } | LispError::ReturnFrom { .. } | LispError::Throw { .. } => return error,
+        LispError::UnboundVariable(name) => symbol_span(form, name).or_else(|| list_span(cell)),
+        _ => list_span(cell),
+    };
+    match span {
+        Some(span) => error.at(&span),
+        None => error,
     }
 }
 
-pub fn eval(expression: &Object, stack: &mut Stack) -> Object {
+fn eval_step(expression: &Object, env: &Environment) -> Result<Tail, LispError> {
     match expression {
-        Object::Atom(atom) => { eval_atom(atom, stack) }
-        Object::List(list) => { eval_list(list, stack) }
-        _ => panic!("Cannot eval improper list")
+        Object::Atom(atom) => { eval_atom(atom, env).map(Tail::Value) }
+        Object::Cons(_) => match expression.to_vec() {
+            Some(list) => eval_list(expression, &list, env),
+            None => Err(LispError::ImproperList(expression.clone())),
+        },
+        Object::Function(_) | Object::Condition(_) => Ok(Tail::Value(expression.clone())),
     }
 }
 
-fn eval_atom(atom: &Atom, stack: &mut Stack) -> Object {
+fn eval_atom(atom: &Atom, env: &Environment) -> Result<Object, LispError> {
     match atom {
-        Atom::Nil => { Object::Atom(atom.clone()) }
-        Atom::T => { Object::Atom(atom.clone()) }
-        Atom::String(_) => { Object::Atom(atom.clone()) }
-        Atom::Integer(_) => { Object::Atom(atom.clone()) }
-        Atom::Float(_) => { Object::Atom(atom.clone()) }
-        Atom::Symbol(_) => { stack.find(atom).unwrap() }
+        Atom::Nil => { Ok(Object::Atom(atom.clone())) }
+        Atom::T => { Ok(Object::Atom(atom.clone())) }
+        Atom::String(_) => { Ok(Object::Atom(atom.clone())) }
+        Atom::Integer(_) => { Ok(Object::Atom(atom.clone())) }
+        Atom::BigInt(_) => { Ok(Object::Atom(atom.clone())) }
+        Atom::Ratio(_) => { Ok(Object::Atom(atom.clone())) }
+        Atom::Float(_) => { Ok(Object::Atom(atom.clone())) }
+        // Keywords such as `:key` evaluate to themselves
+        Atom::Symbol(name) if name.starts_with(':') => { Ok(Object::Atom(atom.clone())) }
+        Atom::Symbol(name) => { env.find(name).ok_or_else(|| LispError::UnboundVariable(name.clone())) }
     }
 }
 
-fn eval_list(list: &Vec<Object>, stack: &mut Stack) -> Object {
-    let mut list_iter = list.iter();
-    if let Some(first) = list_iter.next() {
-        let fn_symbol = match first {
-            Object::Atom(atom) => {
-                match atom {
-                    Atom::Symbol(symbol) => { &symbol[..] }
-                    _ => { panic!("First element of list must be a symbol") }
-                }
+/// Checks that exactly `N` arguments were supplied to `name`
+fn arity<'a, const N: usize>(name: &str, args: &'a [Object]) -> Result<&'a [Object; N], LispError> {
+    args.try_into().map_err(|_| LispError::ArityMismatch {
+        name: name.to_string(),
+        min: N,
+        max: Some(N),
+        found: args.len(),
+    })
+}
+
+fn eval_list(form: &Object, list: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let Some((first, rest)) = list.split_first() else {
+        return Ok(Tail::Value(Object::Atom(Atom::Nil)));
+    };
+    let fn_symbol = match first {
+        Object::Atom(Atom::Symbol(symbol)) => { &symbol[..] }
+        Object::Cons(lambda) if lambda.car() == Object::Atom(Atom::Symbol(String::from("lambda"))) => {
+            let function = eval(first, env)?;
+            let args = rest.iter().map(|o| eval(o, env)).collect::<Result<Vec<_>, _>>()?;
+            return funcall_tail(&function, &args, env);
+        }
+        _ => { return Err(LispError::WrongType { expected: "symbol", found: first.clone() }) }
+    };
+
+    if SPECIAL_FORMS.contains(&fn_symbol) {
+        return eval_special_form(fn_symbol, form, rest, env);
+    }
+    // Macros receive their arguments unevaluated and the code they return is evaluated
+    if let Some(expander) = find_macro(fn_symbol, env) {
+        return Ok(Tail::Call(expand_macro(&expander, rest)?, env.clone()));
+    }
+    let args = rest.iter().map(|o| eval(o, env)).collect::<Result<Vec<_>, _>>()?;
+    if !BUILTINS.contains(&fn_symbol) {
+        return fn_apply_user(fn_symbol, &args, env);
+    }
+    call_function(fn_symbol, &args, env).map(Tail::Value)
+}
+
+/// Evaluates a special form, which receives its arguments unevaluated. Kept apart from
+/// `eval_list` so that ordinary calls, which recurse through it, use less native stack.
+fn eval_special_form(fn_symbol: &str, form: &Object, rest: &[Object], env: &Environment) -> Result<Tail, LispError> {
+    let value = match fn_symbol {
+        "quote" => { let [object] = arity(fn_symbol, rest)?; Ok(object.clone()) }
+        "quasiquote" => { let [template] = arity(fn_symbol, rest)?; fn_quasiquote(template, env) }
+        "setq" => { let [name, value] = arity(fn_symbol, rest)?; fn_setq(name, &eval(value, env)?, env) }
+        "setf" => { let [place, value] = arity(fn_symbol, rest)?; fn_setf(place, value, env) }
+        "defun" => {
+            if rest.len() < 2 {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 2, max: None, found: rest.len() });
             }
-            _ => { panic!("First element of list must be an atom") }
-        };
+            fn_defun(&rest[0], &rest[1], &rest[2..], form, env)
+        }
+        "defmacro" => {
+            if rest.len() < 2 {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 2, max: None, found: rest.len() });
+            }
+            fn_defmacro(&rest[0], &rest[1], &rest[2..], form, env)
+        }
+        "lambda" => {
+            let Some((formals, body)) = rest.split_first() else {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: None, found: 0 });
+            };
+            Ok(fn_lambda(None, formals, body, form, env))
+        }
+        "function" => { let [name] = arity(fn_symbol, rest)?; fn_function(name, env) }
+        "cond" => { return fn_cond(rest, env) }
+        "and" => { fn_and(rest, env) }
+        "or" => { return fn_or(rest, env) }
+        "if" => {
+            return match rest {
+                [test, then] => fn_if(test, then, None, env),
+                [test, then, otherwise] => fn_if(test, then, Some(otherwise), env),
+                _ => Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 2, max: Some(3), found: rest.len() }),
+            };
+        }
+        "when" | "unless" => {
+            let Some((test, body)) = rest.split_first() else {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: None, found: 0 });
+            };
+            return fn_when(test, body, env, fn_symbol == "when");
+        }
+        "progn" => { return eval_body(rest, env) }
+        "dotimes" | "dolist" => {
+            let Some((spec, body)) = rest.split_first() else {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: None, found: 0 });
+            };
+            fn_nil_block(env, |env| if fn_symbol == "dotimes" { fn_dotimes(spec, body, env) } else { fn_dolist(spec, body, env) })
+        }
+        "do" | "do*" => {
+            if rest.len() < 2 {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 2, max: None, found: rest.len() });
+            }
+            fn_nil_block(env, |env| fn_do(&rest[0], &rest[1], &rest[2..], env, fn_symbol == "do*"))
+        }
+        "loop" => { fn_nil_block(env, |env| fn_loop(rest, env)) }
+        "block" | "catch" | "unwind-protect" => {
+            let Some((first, body)) = rest.split_first() else {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: None, found: 0 });
+            };
+            match fn_symbol {
+                "block" => return fn_block(first, body, env),
+                "catch" => fn_catch(first, body, env),
+                _ => fn_unwind_protect(first, body, env),
+            }
+        }
+        "return-from" => {
+            match rest {
+                [name] => fn_return_from(name, None, env),
+                [name, value] => fn_return_from(name, Some(value), env),
+                _ => Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: Some(2), found: rest.len() }),
+            }
+        }
+        "handler-case" => {
+            let Some((form, clauses)) = rest.split_first() else {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: None, found: 0 });
+            };
+            fn_handler_case(form, clauses, env)
+        }
+        "ignore-errors" => { fn_ignore_errors(rest, env) }
+        "return" => {
+            match rest {
+                [] => fn_return_from(&Object::Atom(Atom::Nil), None, env),
+                [value] => fn_return_from(&Object::Atom(Atom::Nil), Some(value), env),
+                _ => Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 0, max: Some(1), found: rest.len() }),
+            }
+        }
+        "prog1" | "prog2" => {
+            let index = if fn_symbol == "prog1" { 0 } else { 1 };
+            if rest.len() <= index {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: index + 1, max: None, found: rest.len() });
+            }
+            fn_prog_n(index, rest, env)
+        }
+        "let" | "let*" | "flet" | "labels" => {
+            let Some((bindings, body)) = rest.split_first() else {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: None, found: 0 });
+            };
+            return match fn_symbol {
+                "let" => fn_let(bindings, body, env),
+                "let*" => fn_let_star(bindings, body, env),
+                "flet" => fn_flet(bindings, body, env, false),
+                _ => fn_flet(bindings, body, env, true),
+            };
+        }
+        _ => unreachable!("{} is not a special form", fn_symbol),
+    };
+    value.map(Tail::Value)
+}
 
-        match fn_symbol {
-            "null" => { fn_null(&eval(list_iter.next().unwrap(), stack)) }
-            "quote" => { list_iter.next().unwrap().clone() }
-            "car" => { fn_car(&eval(list_iter.next().unwrap(), stack)) }
-            "cdr" => { fn_cdr(&eval(list_iter.next().unwrap(), stack)) }
-            "cons" => { fn_cons(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            "print" => { fn_print(&eval(list_iter.next().unwrap(), stack)) }
-            "atom" => { fn_atom(&eval(list_iter.next().unwrap(), stack)) }
-            "listp" => { fn_listp(&eval(list_iter.next().unwrap(), stack)) }
-            "setq" => { fn_setq(list_iter.next().unwrap(), &eval(list_iter.next().unwrap(), stack), stack) }
-            "defun" => { fn_defun(list_iter.next().unwrap(), list_iter.next().unwrap(), &Object::List(list_iter.cloned().collect()), stack) }
-            "cond" => { fn_cond(&Object::List(list_iter.cloned().collect()), stack) }
-            "eq" => { fn_eq(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            "eval" => { eval(&eval(list_iter.next().unwrap(), stack), stack) }
-            "equal" => { fn_equal(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            "+" => { fn_add(&list_iter.map(|o| eval(o, stack)).collect()) }
-            "-" => { fn_subtract(&list_iter.map(|o| eval(o, stack)).collect()) }
-            "*" => { fn_multiply(&list_iter.map(|o| eval(o, stack)).collect()) }
-            "/" => { fn_divide(&list_iter.map(|o| eval(o, stack)).collect()) }
-            "mod" => { fn_mod(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            "floor" => { fn_floor(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap_or(&Object::Atom(Atom::Integer(1))), stack)) }
-            "apply" => { fn_apply(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack), stack) }
-            "load" => { fn_load(&eval(list_iter.next().unwrap(), stack), stack) }
-            "and" => { fn_and(&Object::List(list_iter.cloned().collect()), stack) }
-            "<=" => { fn_less_than_or_equal(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            ">=" => { fn_greater_than_or_equal(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            ">" => { fn_greater_than(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            "<" => { fn_less_than(&eval(list_iter.next().unwrap(), stack), &eval(list_iter.next().unwrap(), stack)) }
-            _ => { fn_apply_user(first, &Object::List(list_iter.cloned().collect()), stack) }
+/// Calls a builtin or user defined function with already evaluated arguments
+pub fn call_function(fn_symbol: &str, args: &[Object], env: &Environment) -> Result<Object, LispError> {
+    match fn_symbol {
+        "null" => { let [a] = arity(fn_symbol, args)?; Ok(fn_null(a)) }
+        "not" => { let [a] = arity(fn_symbol, args)?; Ok(fn_not(a)) }
+        "car" => { let [a] = arity(fn_symbol, args)?; fn_car(a) }
+        "cdr" => { let [a] = arity(fn_symbol, args)?; fn_cdr(a) }
+        "cons" => { let [a, b] = arity(fn_symbol, args)?; Ok(fn_cons(a, b)) }
+        "rplaca" => { let [cons, car] = arity(fn_symbol, args)?; fn_rplaca(cons, car) }
+        "rplacd" => { let [cons, cdr] = arity(fn_symbol, args)?; fn_rplacd(cons, cdr) }
+        "nconc" => { fn_nconc(args) }
+        "list" => { Ok(fn_list(args)) }
+        "print" => { let [a] = arity(fn_symbol, args)?; Ok(fn_print(a)) }
+        "prin1" => { let [a] = arity(fn_symbol, args)?; Ok(fn_prin1(a)) }
+        "princ" => { let [a] = arity(fn_symbol, args)?; Ok(fn_princ(a)) }
+        "pprint" => { let [a] = arity(fn_symbol, args)?; Ok(fn_pprint(a, env)) }
+        "read" => { let [] = arity(fn_symbol, args)?; fn_read() }
+        "read-from-string" => { let [a] = arity(fn_symbol, args)?; fn_read_from_string(a) }
+        "atom" => { let [a] = arity(fn_symbol, args)?; Ok(fn_atom(a)) }
+        "listp" => { let [a] = arity(fn_symbol, args)?; Ok(fn_listp(a)) }
+        "eq" => { let [a, b] = arity(fn_symbol, args)?; Ok(fn_eq(a, b)) }
+        "eval" => { let [a] = arity(fn_symbol, args)?; eval(a, env) }
+        "equal" => { let [a, b] = arity(fn_symbol, args)?; Ok(fn_equal(a, b)) }
+        "+" => { fn_add(args) }
+        "-" => { fn_subtract(args) }
+        "*" => { fn_multiply(args) }
+        "/" => { fn_divide(args) }
+        "mod" => { let [a, b] = arity(fn_symbol, args)?; fn_mod(a, b) }
+        "floor" => {
+            match args {
+                [a] => fn_floor(a, &Object::Atom(Atom::Integer(1))),
+                [a, b] => fn_floor(a, b),
+                _ => Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: Some(2), found: args.len() })
+            }
         }
-    } else {
-        Object::Atom(Atom::Nil)
+        "apply" => { let [a, b] = arity(fn_symbol, args)?; fn_apply(a, b, env) }
+        "funcall" => {
+            let Some((function, args)) = args.split_first() else {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: None, found: 0 });
+            };
+            fn_funcall(function, args, env)
+        }
+        "load" => { let [a] = arity(fn_symbol, args)?; fn_load(a, env) }
+        "throw" => { let [a, b] = arity(fn_symbol, args)?; fn_throw(a, b) }
+        "error" | "warn" | "signal" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err(LispError::ArityMismatch { name: fn_symbol.to_string(), min: 1, max: Some(2), found: args.len() });
+            }
+            fn_signal(fn_symbol, args, env)
+        }
+        "condition-message" => { let [a] = arity(fn_symbol, args)?; fn_condition_message(a) }
+        "condition-type" => { let [a] = arity(fn_symbol, args)?; fn_condition_type(a) }
+        "<=" => { let [a, b] = arity(fn_symbol, args)?; fn_less_than_or_equal(a, b) }
+        ">=" => { let [a, b] = arity(fn_symbol, args)?; fn_greater_than_or_equal(a, b) }
+        ">" => { let [a, b] = arity(fn_symbol, args)?; fn_greater_than(a, b) }
+        "<" => { let [a, b] = arity(fn_symbol, args)?; fn_less_than(a, b) }
+        "macroexpand" => { let [a] = arity(fn_symbol, args)?; fn_macroexpand(a, env) }
+        "macroexpand-1" => { let [a] = arity(fn_symbol, args)?; fn_macroexpand_1(a, env) }
+        "gc" => { let [] = arity(fn_symbol, args)?; Ok(fn_gc()) }
+        "room" => { let [] = arity(fn_symbol, args)?; Ok(fn_room()) }
+        _ => { fn_apply_user(fn_symbol, args, env)?.into_value() }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parser::{ConsCell, parse};
-    use crate::tokenizer::{MyFloat, tokenize_expr};
+    use crate::parser::{parse_all, parse_source};
+    use crate::number::Number;
+    use crate::tokenizer::MyFloat;
 
     use super::*;
 
     fn expr(s: &str) -> Object {
-        parse(&mut tokenize_expr(s).iter().peekable())
+        parse_all(s).unwrap().remove(0)
     }
 
     #[test]
     fn test_eval_quote() {
-        let expr = parse(&mut tokenize_expr("(quote (1 2))").iter().peekable());
-        let result = eval(&expr, &mut Stack::new());
-        let expected = Object::List(vec![
+        let expr = parse_all("(quote (1 2))").unwrap().remove(0);
+        let result = eval(&expr, &Environment::new()).unwrap();
+        let expected = Object::list(vec![
             Object::Atom(Atom::Integer(1)),
             Object::Atom(Atom::Integer(2)),
         ]);
@@ -112,221 +586,964 @@ mod tests {
 
     #[test]
     fn test_eval_null() {
-        let expr = parse(&mut tokenize_expr("(null '(1 2))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        let expr = parse_all("(null '(1 2))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
 
-        let expr = parse(&mut tokenize_expr("(null nil)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
+        let expr = parse_all("(null nil)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
     }
 
     #[test]
     fn test_eval_atom() {
-        let expr = parse(&mut tokenize_expr("5").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Integer(5)));
+        let expr = parse_all("5").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(5))));
     }
 
     #[test]
     fn test_eval_car() {
-        let expr = parse(&mut tokenize_expr("(car nil)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        let expr = parse_all("(car nil)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
 
-        let expr = parse(&mut tokenize_expr("(car ())").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        let expr = parse_all("(car ())").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
 
-        let expr = parse(&mut tokenize_expr("(car '(5))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Integer(5)));
+        let expr = parse_all("(car '(5))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(5))));
     }
 
     #[test]
     fn test_eval_cdr() {
-        let expr = parse(&mut tokenize_expr("(cdr nil)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        let expr = parse_all("(cdr nil)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
 
-        let expr = parse(&mut tokenize_expr("(cdr '(1 2))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::List(vec![Object::Atom(Atom::Integer(2))]));
+        let expr = parse_all("(cdr '(1 2))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::list(vec![Object::Atom(Atom::Integer(2))])));
 
-        let expr = parse(&mut tokenize_expr("(null (cdr '(1)))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
+        let expr = parse_all("(null (cdr '(1)))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
     }
 
     #[test]
     fn test_eval_print() {
-        let expr = parse(&mut tokenize_expr("(print '(1 2))").iter().peekable());
-        let result = eval(&expr, &mut Stack::new());
-        if let Object::Atom(Atom::String(s)) = result {
-            assert_eq!(s, "(1 2)");
-        } else {
-            panic!("Expected string");
-        }
+        let expr = parse_all("(print '(1 2))").unwrap().remove(0);
+        let result = eval(&expr, &Environment::new()).unwrap();
+        assert_eq!(fn_print_helper(&result), "(1 2)");
 
-        let expr = parse(&mut tokenize_expr("(print 'foo)").iter().peekable());
-        let result = eval(&expr, &mut Stack::new());
-        if let Object::Atom(Atom::String(s)) = result {
-            assert_eq!(s, "foo");
-        } else {
-            panic!("Expected string");
-        }
+        let expr = parse_all("(print 'foo)").unwrap().remove(0);
+        let result = eval(&expr, &Environment::new()).unwrap();
+        assert_eq!(result, Object::Atom(Atom::Symbol(String::from("foo"))));
     }
 
     #[test]
     fn test_eval_fn_atom() {
-        let expr = parse(&mut tokenize_expr("(atom '(1 2))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        let expr = parse_all("(atom '(1 2))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
 
-        let expr = parse(&mut tokenize_expr("(atom 100)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
+        let expr = parse_all("(atom 100)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
     }
 
     #[test]
     fn test_eval_listp() {
-        let expr = parse(&mut tokenize_expr("(listp '(1 2))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
+        let expr = parse_all("(listp '(1 2))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
 
-        let expr = parse(&mut tokenize_expr("(listp 100)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        let expr = parse_all("(listp 100)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
     }
 
     #[test]
     fn test_setq() {
-        let mut stack = Stack::new();
-        let expr = parse(&mut tokenize_expr("(setq x 5)").iter().peekable());
-        assert_eq!(eval(&expr, &mut stack), Object::Atom(Atom::Integer(5)));
-        let expr = parse(&mut tokenize_expr("x").iter().peekable());
-        assert_eq!(eval(&expr, &mut stack), Object::Atom(Atom::Integer(5)));
+        let env = Environment::new();
+        let expr = parse_all("(setq x 5)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &env), Ok(Object::Atom(Atom::Integer(5))));
+        let expr = parse_all("x").unwrap().remove(0);
+        assert_eq!(eval(&expr, &env), Ok(Object::Atom(Atom::Integer(5))));
     }
 
     #[test]
     fn test_defun() {
-        let mut stack = Stack::new();
-        let expr = parse(&mut tokenize_expr("(defun join (x y) (print y) (cons x y))").iter().peekable());
-        assert_eq!(eval(&expr, &mut stack), Object::Atom(Atom::Symbol(String::from("join"))));
-        let expr = parse(&mut tokenize_expr("(join (quote a) 5)").iter().peekable());
-        assert_eq!(eval(&expr, &mut stack), Object::ConsCell(Box::new(ConsCell::new(Object::Atom(Atom::Symbol(String::from("a"))), Object::Atom(Atom::Integer(5))))));
+        let env = Environment::new();
+        let expr = parse_all("(defun join (x y) (print y) (cons x y))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &env), Ok(Object::Atom(Atom::Symbol(String::from("join")))));
+        let expr = parse_all("(join (quote a) 5)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &env), Ok(Object::cons(Object::Atom(Atom::Symbol(String::from("a"))), Object::Atom(Atom::Integer(5)))));
     }
 
     #[test]
     fn test_cond() {
-        let expr = parse(&mut tokenize_expr("(cond ((null 5) T) (T Nil))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
-        let expr = parse(&mut tokenize_expr("(cond ((null ()) T) (T Nil))").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
+        let expr = parse_all("(cond ((null 5) T) (T Nil))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
+        let expr = parse_all("(cond ((null ()) T) (T Nil))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
     }
 
     #[test]
     fn test_eq() {
-        let expr = parse(&mut tokenize_expr("(eq 'a 'b)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Nil));
-        let expr = parse(&mut tokenize_expr("(eq 'a 'a)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
-        let expr = parse(&mut tokenize_expr("(eq nil nil)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
-        let expr = parse(&mut tokenize_expr("(eq T T)").iter().peekable());
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
+        let expr = parse_all("(eq 'a 'b)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
+        let expr = parse_all("(eq 'a 'a)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
+        let expr = parse_all("(eq nil nil)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
+        let expr = parse_all("(eq T T)").unwrap().remove(0);
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
+
+        let env = Environment::new();
+        eval(&parse_all("(setq f (lambda (x) x))").unwrap().remove(0), &env).unwrap();
+        let expr = parse_all("(list (eq f f) (eq f (lambda (x) x)) (eq #'car #'car) (eq #'car #'cdr))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &env), Ok(parse_all("(t nil t nil)").unwrap().remove(0)));
+        let expr = parse_all("(catch f (throw f 'caught))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &env), Ok(Object::Atom(Atom::Symbol(String::from("caught")))));
+        let expr = parse_all("(handler-case (error \"boom\") (error (c) (eq c c)))").unwrap().remove(0);
+        assert_eq!(eval(&expr, &env), Ok(Object::Atom(Atom::T)));
     }
 
     #[test]
     fn test_eval() {
         let expr = expr("(eval '(car '(1 2)))");
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::Integer(1)));
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(1))));
     }
 
     #[test]
     fn test_equal() {
         let expr = expr("(equal '(1 2) '(1 2))");
-        assert_eq!(eval(&expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
     }
 
     #[test]
     fn test_greater_than() {
         let test_expr = expr("(> 5 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
         let test_expr = expr("(> 4 5)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
     }
 
     #[test]
     fn test_less_than() {
         let test_expr = expr("(< 4 5)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
         let test_expr = expr("(< 5 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
     }
 
     #[test]
     fn test_less_than_or_equal() {
         let test_expr = expr("(<= 3 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
         let test_expr = expr("(<= 4 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
         let test_expr = expr("(<= 5 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
     }
 
     #[test]
     fn test_greater_than_or_equal() {
         let test_expr = expr("(>= 5 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
         let test_expr = expr("(>= 4 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
         let test_expr = expr("(>= 3 4)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Nil));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Nil)));
     }
 
     #[test]
     fn test_add() {
         let test_expr = expr("(+ 1 2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Float(MyFloat(3.0))));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(3))));
         let test_expr = expr("(+ 1 2.5)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Float(MyFloat(3.5))));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Float(MyFloat(3.5)))));
     }
 
     #[test]
     fn test_sub() {
         let test_expr = expr("(- 1 2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Float(MyFloat(-1.0))));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(-1))));
         let test_expr = expr("(- 1 0.5 0.25)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Float(MyFloat(0.25))));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Float(MyFloat(0.25)))));
+        let test_expr = expr("(- 5)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(-5))));
     }
 
     #[test]
     fn test_multiply() {
         let test_expr = expr("(* 1 2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Float(MyFloat(2.0))));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(2))));
+        let test_expr = expr("(* 2 1.5)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Float(MyFloat(3.0)))));
     }
 
     #[test]
     fn test_divide() {
+        let test_expr = expr("(/ 6 2)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(3))));
+        let test_expr = expr("(/ 12 2 3)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(2))));
         let test_expr = expr("(/ 1 2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Float(MyFloat(0.5))));
+        assert_eq!(fn_print_helper(&eval(&test_expr, &Environment::new()).unwrap()), "1/2");
+        let test_expr = expr("(/ 1 2.0)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Float(MyFloat(0.5)))));
+        let test_expr = expr("(/ 4)");
+        assert_eq!(fn_print_helper(&eval(&test_expr, &Environment::new()).unwrap()), "1/4");
     }
 
     #[test]
     fn test_mod() {
         let test_expr = expr("(mod 1 2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Integer(1)));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(1))));
         let test_expr = expr("(mod 5 2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Integer(1)));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(1))));
+        let test_expr = expr("(mod -1 2)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(1))));
     }
 
     #[test]
     fn test_floor() {
         let test_expr = expr("(floor 1 2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Integer(0)));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(0))));
         let test_expr = expr("(floor 3.2)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Integer(3)));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(3))));
         let test_expr = expr("(floor 5 3)");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Integer(1)));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(1))));
     }
 
     #[test]
     fn test_apply() {
         let test_expr = expr("(apply '+ '(1 2))");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::Float(MyFloat(3.0))));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Integer(3))));
     }
 
     #[test]
     fn test_load() {
         let test_expr = expr("(load \"test.l\")");
-        assert_eq!(eval(&test_expr, &mut Stack::new()), Object::Atom(Atom::T));
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::T)));
+    }
+
+    #[test]
+    fn test_unbound_variable() {
+        let test_expr = expr("(car x)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Err(LispError::UnboundVariable(String::from("x"))));
+        let test_expr = expr("(foo 1)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Err(LispError::UndefinedFunction(String::from("foo"))));
+    }
+
+    #[test]
+    fn test_arity_mismatch() {
+        let test_expr = expr("(cons 1)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Err(LispError::ArityMismatch {
+            name: String::from("cons"),
+            min: 2,
+            max: Some(2),
+            found: 1,
+        }));
+
+        let env = Environment::new();
+        eval(&expr("(defun f (x) x)"), &env).unwrap();
+        assert_eq!(eval(&expr("(f 1 2)"), &env), Err(LispError::ArityMismatch {
+            name: String::from("f"),
+            min: 1,
+            max: Some(1),
+            found: 2,
+        }));
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let test_expr = expr("(+ 1 'a)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Err(LispError::WrongType {
+            expected: "number",
+            found: Object::Atom(Atom::Symbol(String::from("a"))),
+        }));
+        let test_expr = expr("(car 5)");
+        assert!(matches!(eval(&test_expr, &Environment::new()), Err(LispError::WrongType { .. })));
+        let test_expr = expr("(mod 5 0)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Err(LispError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let test_expr = expr("(load \"missing.l\")");
+        assert_eq!(eval(&test_expr, &Environment::new()), Err(LispError::FileNotFound(String::from("missing.l"))));
+    }
+
+    #[test]
+    fn test_stack_survives_error() {
+        let env = Environment::new();
+        eval(&expr("(setq x 5)"), &env).unwrap();
+        assert!(eval(&expr("(+ x y)"), &env).is_err());
+        assert_eq!(eval(&expr("x"), &env), Ok(Object::Atom(Atom::Integer(5))));
+    }
+
+    #[test]
+    fn test_lambda() {
+        let test_expr = expr("((lambda (x y) (cons y x)) 1 2)");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::cons(
+            Object::Atom(Atom::Integer(2)),
+            Object::Atom(Atom::Integer(1)),
+        )));
+        let test_expr = expr("(funcall (lambda (x) (car x)) '(a b))");
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::Symbol(String::from("a")))));
+    }
+
+    #[test]
+    fn test_closure_captures_environment() {
+        let env = Environment::new();
+        eval(&expr("(defun make-adder (n) (lambda (x) (+ n x)))"), &env).unwrap();
+        eval(&expr("(setq add5 (make-adder 5))"), &env).unwrap();
+        eval(&expr("(setq n 100)"), &env).unwrap();
+        assert_eq!(eval(&expr("(funcall add5 1)"), &env), Ok(Object::Atom(Atom::Integer(6))));
+    }
+
+    #[test]
+    fn test_lexical_scope() {
+        let env = Environment::new();
+        eval(&expr("(defun get-x () x)"), &env).unwrap();
+        eval(&expr("(defun f (x) (get-x))"), &env).unwrap();
+        assert_eq!(eval(&expr("(f 1)"), &env), Err(LispError::UnboundVariable(String::from("x"))));
+        eval(&expr("(setq x 2)"), &env).unwrap();
+        assert_eq!(eval(&expr("(f 1)"), &env), Ok(Object::Atom(Atom::Integer(2))));
+    }
+
+    #[test]
+    fn test_function() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(funcall #'car '(1 2))"), &env), Ok(Object::Atom(Atom::Integer(1))));
+        eval(&expr("(defun second (l) (car (cdr l)))"), &env).unwrap();
+        assert_eq!(eval(&expr("(apply (function second) '((1 2)))"), &env), Ok(Object::Atom(Atom::Integer(2))));
+        assert_eq!(eval(&expr("(function missing)"), &env), Err(LispError::UndefinedFunction(String::from("missing"))));
+    }
+
+    #[test]
+    fn test_mapcar_lambda() {
+        let env = Environment::new();
+        eval(&expr("(load \"builtin.l\")"), &env).unwrap();
+        assert_eq!(eval(&expr("(mapcar (lambda (x) (cons x nil)) '(a b))"), &env), Ok(Object::list(vec![
+            Object::list(vec![Object::Atom(Atom::Symbol(String::from("a")))]),
+            Object::list(vec![Object::Atom(Atom::Symbol(String::from("b")))]),
+        ])));
+        assert_eq!(eval(&expr("(mapcar #'car '((1) (2)))"), &env), Ok(Object::list(vec![
+            Object::Atom(Atom::Integer(1)),
+            Object::Atom(Atom::Integer(2)),
+        ])));
+    }
+
+    #[test]
+    fn test_environment_chain() {
+        let global = Environment::new();
+        global.define("x", Object::Atom(Atom::Integer(1)));
+        let inner = global.extend();
+        inner.define("y", Object::Atom(Atom::Integer(2)));
+        assert_eq!(inner.find("x"), Some(Object::Atom(Atom::Integer(1))));
+        assert_eq!(global.find("y"), None);
+
+        inner.set("x", Object::Atom(Atom::Integer(3)));
+        inner.set("z", Object::Atom(Atom::Integer(4)));
+        assert_eq!(global.find("x"), Some(Object::Atom(Atom::Integer(3))));
+        assert_eq!(global.find("z"), Some(Object::Atom(Atom::Integer(4))));
+    }
+
+    #[test]
+    fn test_setq_updates_closure_binding() {
+        let env = Environment::new();
+        eval(&expr("(defun make-counter () ((lambda (count) (lambda () (setq count (+ count 1)))) 0))"), &env).unwrap();
+        eval(&expr("(setq counter (make-counter))"), &env).unwrap();
+        eval(&expr("(funcall counter)"), &env).unwrap();
+        assert_eq!(eval(&expr("(funcall counter)"), &env), Ok(Object::Atom(Atom::Integer(2))));
+        assert_eq!(eval(&expr("count"), &env), Err(LispError::UnboundVariable(String::from("count"))));
+    }
+
+    #[test]
+    fn test_bignum_promotion() {
+        let env = Environment::new();
+        let test_expr = expr("(* 65536 65536)");
+        assert_eq!(fn_print_helper(&eval(&test_expr, &env).unwrap()), "4294967296");
+        let test_expr = expr("(- (* 65536 65536) (* 65536 65536) 1)");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Integer(-1))));
+
+        eval(&expr("(defun fact (n) (cond ((<= n 1) 1) (t (* n (fact (- n 1))))))"), &env).unwrap();
+        assert_eq!(fn_print_helper(&eval(&expr("(fact 25)"), &env).unwrap()), "15511210043330985984000000");
+        let test_expr = expr("(/ (fact 25) (fact 24))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Integer(25))));
+    }
+
+    #[test]
+    fn test_ratio_literals() {
+        let env = Environment::new();
+        assert_eq!(fn_print_helper(&eval(&expr("(+ 1/4 1/2)"), &env).unwrap()), "3/4");
+        assert_eq!(eval(&expr("(* 3/4 4/3)"), &env), Ok(Object::Atom(Atom::Integer(1))));
+        assert_eq!(eval(&expr("(< 1/3 0.34)"), &env), Ok(Object::Atom(Atom::T)));
+        assert_eq!(fn_print_helper(&eval(&expr("(/ 123456789012345678901234567890 4)"), &env).unwrap()), "61728394506172839450617283945/2");
+    }
+
+    #[test]
+    fn test_nth_after_arithmetic() {
+        let env = Environment::new();
+        eval(&expr("(load \"builtin.l\")"), &env).unwrap();
+        assert_eq!(eval(&expr("(car (nth '(a b c) (+ 1 1)))"), &env), Ok(Object::Atom(Atom::Symbol(String::from("b")))));
+    }
+
+    #[test]
+    fn test_print_escapes_strings() {
+        let test_expr = expr(r#"(print "say \"hi\"\n")"#);
+        assert_eq!(eval(&test_expr, &Environment::new()), Ok(Object::Atom(Atom::String(String::from("say \"hi\"\n")))));
+        let printed = fn_print_helper(&eval(&expr(r#"'("a b" c "\\")"#), &Environment::new()).unwrap());
+        assert_eq!(printed, r#"("a b" c "\\")"#);
+        assert_eq!(eval(&expr(&format!("'{}", printed)), &Environment::new()).map(|o| fn_print_helper(&o)), Ok(printed));
+    }
+
+    #[test]
+    fn test_printers_and_readers() {
+        let env = Environment::new();
+        let list = expr(r#"("a\"b" c 1/2)"#);
+        assert_eq!(eval(&expr(r#"(prin1 '("a\"b" c 1/2))"#), &env), Ok(list.clone()));
+        assert_eq!(eval(&expr(r#"(princ '("a\"b" c 1/2))"#), &env), Ok(list.clone()));
+        assert_eq!(fn_print_helper(&list), r#"("a\"b" c 1/2)"#);
+        assert_eq!(princ_to_string(&list), r#"(a"b c 1/2)"#);
+        assert_eq!(princ_to_string(&eval(&expr("(handler-case (error \"oops\") (error (e) e))"), &env).unwrap()), "oops");
+
+        assert_eq!(eval(&expr(r#"(read-from-string "(a . (b \"c\")) d")"#), &env), Ok(expr(r#"(a b "c")"#)));
+        assert_eq!(eval(&expr(r#"(read-from-string "  ")"#), &env), Err(LispError::EndOfFile));
+        assert!(matches!(eval(&expr(r#"(read-from-string "(a")"#), &env), Err(LispError::ParseError(_))));
+        assert_eq!(eval(&expr(r#"(handler-case (read-from-string "") (end-of-file () 'done))"#), &env), Ok(expr("done")));
+    }
+
+    /// A small deterministic generator of readable objects for round trip tests
+    struct ObjectGenerator(u64);
+
+    impl ObjectGenerator {
+        fn next(&mut self, bound: u64) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+
+        fn symbol(&mut self) -> String {
+            let letters = "abcdefghijklmnopqrstuvwxyz";
+            let others = "abcxyz0123456789-*+?<=>";
+            let mut name = letters.chars().nth(self.next(26) as usize).unwrap().to_string();
+            for _ in 0..self.next(6) {
+                name.push(others.chars().nth(self.next(others.len() as u64) as usize).unwrap());
+            }
+            if name == "t" || name == "nil" { name.push('x'); }
+            name
+        }
+
+        fn string(&mut self) -> String {
+            let chars = ['a', 'Z', ' ', '"', '\\', '\n', '\t', '\r', '\u{1}', '\u{7f}', 'é', '😀', ';', '(', ')'];
+            (0..self.next(8)).map(|_| chars[self.next(chars.len() as u64) as usize]).collect()
+        }
+
+        fn atom(&mut self) -> Object {
+            match self.next(8) {
+                0 => Object::Atom(Atom::Integer(self.next(u64::MAX) as i32)),
+                1 => Number::from(self.next(u64::MAX) as usize).mul(&Number::from(self.next(u64::MAX) as usize)).into_object(),
+                2 => Number::Integer(self.next(1000) as i32 - 500).div(&Number::Integer(self.next(99) as i32 + 1)).unwrap().into_object(),
+                3 => {
+                    let specials = [f64::INFINITY, f64::NEG_INFINITY, f64::NAN, -0.0, f64::MIN_POSITIVE / 3.0];
+                    let float = match self.next(4) {
+                        0 => specials[self.next(specials.len() as u64) as usize],
+                        _ => f64::from_bits(self.next(u64::MAX)),
+                    };
+                    // Every NaN is written the same way
+                    Object::Atom(Atom::Float(MyFloat(if float.is_nan() { f64::NAN } else { float })))
+                }
+                4 => Object::Atom(Atom::Symbol(self.symbol())),
+                5 => Object::Atom(Atom::String(self.string())),
+                6 => Object::Atom(Atom::T),
+                _ => Object::Atom(Atom::Nil),
+            }
+        }
+
+        fn object(&mut self, depth: u32) -> Object {
+            if depth == 0 || self.next(3) == 0 {
+                return self.atom();
+            }
+            let items = (0..self.next(5)).map(|_| self.object(depth - 1)).collect::<Vec<_>>();
+            if !items.is_empty() && self.next(4) == 0 {
+                Object::dotted_list(items, self.atom())
+            } else {
+                Object::list(items)
+            }
+        }
+    }
+
+    #[test]
+    fn test_print_read_round_trip() {
+        let mut generator = ObjectGenerator(0x2545f4914f6cdd1d);
+        for _ in 0..2000 {
+            let object = generator.object(4);
+            let printed = fn_print_helper(&object);
+            let read = fn_read_from_string(&Object::Atom(Atom::String(printed.clone()))).unwrap();
+            assert!(identical(&read, &object), "reading {} gave {}", printed, fn_print_helper(&read));
+        }
+    }
+
+    /// Structural equality that compares floats by their bits rather than approximately
+    fn identical(a: &Object, b: &Object) -> bool {
+        match (a, b) {
+            (Object::Atom(Atom::Float(x)), Object::Atom(Atom::Float(y))) => x.0.to_bits() == y.0.to_bits(),
+            (Object::Cons(x), Object::Cons(y)) => identical(&x.car(), &y.car()) && identical(&x.cdr(), &y.cdr()),
+            _ => a == b,
+        }
+    }
+
+    #[test]
+    fn test_dotted_lists() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("'(a . b)"), &env), Ok(eval(&expr("(cons 'a 'b)"), &env).unwrap()));
+        assert_eq!(eval(&expr("(cdr (cdr '(1 2 . 3)))"), &env), Ok(Object::Atom(Atom::Integer(3))));
+        assert_eq!(eval(&expr("'(1 . (2 3))"), &env), Ok(expr("(1 2 3)")));
+        let printed = fn_print_helper(&eval(&expr("(cons 1 (cons 2 3))"), &env).unwrap());
+        assert_eq!(printed, "(1 2 . 3)");
+        assert_eq!(eval(&expr(&format!("'{}", printed)), &env).map(|o| fn_print_helper(&o)), Ok(printed));
+
+        let error = eval(&expr("(+ 1 . 2)"), &env).unwrap_err();
+        assert_eq!(error, LispError::ImproperList(expr("(+ 1 . 2)")));
+        assert_eq!(error.to_string(), "Cannot evaluate dotted list (+ 1 . 2)");
+    }
+
+    #[test]
+    fn test_load_error_location() {
+        let path = std::env::temp_dir().join("lisp_test_load_error_location.l");
+        std::fs::write(&path, "(setq x 1)\n\n(defun f ()\n  y)\n  (f)\n").unwrap();
+        let test_expr = Object::list(vec![
+            Object::Atom(Atom::Symbol(String::from("load"))),
+            Object::Atom(Atom::String(path.to_string_lossy().to_string())),
+        ]);
+        let error = eval(&test_expr, &Environment::new()).unwrap_err();
+        let LispError::Located { span, error } = error else {
+            panic!("Expected located error");
+        };
+        assert_eq!(span.file.as_deref(), Some(&*path.to_string_lossy()));
+        assert_eq!((span.line, span.column), (4, 3));
+        assert_eq!(*error, LispError::UnboundVariable(String::from("y")));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_inner_error_location() {
+        // Errors are reported at the innermost form, or variable, with a known position
+        let locate = |source: &str| {
+            let env = Environment::new();
+            let mut located = None;
+            for form in parse_source(source, None).unwrap() {
+                if let Err(error) = eval(&form.object, &env) {
+                    located = Some(error);
+                }
+            }
+            match located {
+                Some(LispError::Located { span, error }) => (span.line, span.column, *error),
+                error => panic!("Expected located error, got {:?}", error),
+            }
+        };
+        let (line, column, error) = locate("(defun g (l)\n  (+ 1\n     (car l)))\n(g 5)");
+        assert_eq!((line, column), (3, 6));
+        assert!(matches!(error, LispError::WrongType { expected: "list", .. }));
+        assert_eq!(locate("(let ((a 1)\n      (b zz))\n  b)"), (2, 10, LispError::UnboundVariable(String::from("zz"))));
+        assert_eq!(locate("(if t\n    nope)"), (2, 5, LispError::UnboundVariable(String::from("nope"))));
+        assert_eq!(locate("(list 'q\n      q)"), (2, 7, LispError::UnboundVariable(String::from("q"))));
+        assert_eq!(locate("(funcall (lambda () (progn 1\n  w)))"), (2, 3, LispError::UnboundVariable(String::from("w"))));
+        // Code that wasn't read from source has no position
+        assert_eq!(eval(&expr("(progn undefined)"), &Environment::new()), Err(LispError::UnboundVariable(String::from("undefined"))));
+    }
+
+    #[test]
+    fn test_defmacro() {
+        let env = Environment::new();
+        let test_expr = expr("(defmacro my-if (test then else) (list 'cond (list test then) (list t else)))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Symbol(String::from("my-if")))));
+        assert_eq!(eval(&expr("(my-if (< 1 2) 'yes undefined)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("yes")))));
+        assert_eq!(eval(&expr("(my-if nil undefined 'no)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("no")))));
+
+        eval(&expr("(defmacro my-quote (x) (list 'quote x))"), &env).unwrap();
+        assert_eq!(eval(&expr("(my-quote (a b))"), &env), Ok(expr("(a b)")));
+        assert_eq!(eval(&expr("(my-if t 1)"), &env), Err(LispError::ArityMismatch {
+            name: String::from("my-if"),
+            min: 3,
+            max: Some(3),
+            found: 2,
+        }));
+    }
+
+    #[test]
+    fn test_macroexpand() {
+        let env = Environment::new();
+        eval(&expr("(defmacro my-unless (test form) (list 'my-if test nil form))"), &env).unwrap();
+        eval(&expr("(defmacro my-if (test then else) (list 'cond (list test then) (list t else)))"), &env).unwrap();
+        assert_eq!(eval(&expr("(macroexpand-1 '(my-unless x 5))"), &env), Ok(expr("(my-if x nil 5)")));
+        assert_eq!(eval(&expr("(macroexpand '(my-unless x 5))"), &env), Ok(expr("(cond (x nil) (t 5))")));
+        assert_eq!(eval(&expr("(macroexpand '(car x))"), &env), Ok(expr("(car x)")));
+        assert_eq!(eval(&expr("(my-unless (null 1) 'ok)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("ok")))));
+        assert!(matches!(eval(&expr("(funcall #'my-if t 1 2)"), &env), Err(LispError::WrongType { expected: "function", .. })));
+    }
+
+    #[test]
+    fn test_quasiquote() {
+        let env = Environment::new();
+        eval(&expr("(setq x 5)"), &env).unwrap();
+        eval(&expr("(setq xs '(1 2))"), &env).unwrap();
+        assert_eq!(eval(&expr("`(a ,x ,@xs b)"), &env), Ok(expr("(a 5 1 2 b)")));
+        assert_eq!(eval(&expr("`(a (b ,(+ x 1)) ,@nil)"), &env), Ok(expr("(a (b 6))")));
+        assert_eq!(eval(&expr("`x"), &env), Ok(expr("x")));
+        assert_eq!(eval(&expr("`(,@xs)"), &env), Ok(expr("(1 2)")));
+        assert_eq!(eval(&expr("`(a `(b ,(c ,x)))"), &env), Ok(expr("(a (quasiquote (b (unquote (c 5)))))")));
+        assert!(matches!(eval(&expr("`(,@x)"), &env), Err(LispError::WrongType { expected: "list", .. })));
+
+        // Dotted templates, including an unquoted tail
+        assert_eq!(eval(&expr("`(a . ,x)"), &env), Ok(expr("(a . 5)")));
+        assert_eq!(eval(&expr("`(,x . b)"), &env), Ok(expr("(5 . b)")));
+        assert_eq!(eval(&expr("`(1 ,@'(2 3) . 4)"), &env), Ok(expr("(1 2 3 . 4)")));
+        assert_eq!(eval(&expr("`(0 ,@xs . ,xs)"), &env), Ok(expr("(0 1 2 1 2)")));
+        assert!(matches!(eval(&expr("`(a . ,@xs)"), &env), Err(LispError::WrongType { expected: "list element for unquote-splicing", .. })));
+
+        eval(&expr("(defmacro swap-args (call) `(,(car call) ,@(cdr (cdr call)) ,(car (cdr call))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(swap-args (- 1 10))"), &env), Ok(Object::Atom(Atom::Integer(9))));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let env = Environment::new();
+        eval(&expr("(defun count-down (n) (cond ((<= n 0) 'done) (t (count-down (- n 1)))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(count-down 100000)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("done")))));
+
+        eval(&expr("(defun even (n) (cond ((<= n 0) t) (t (odd (- n 1)))))"), &env).unwrap();
+        eval(&expr("(defun odd (n) (cond ((<= n 0) nil) (t (even (- n 1)))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(even 10001)"), &env), Ok(Object::Atom(Atom::Nil)));
+
+        let test_expr = expr("((lambda (n) (cond ((<= n 0) 'done) (t (count-down n)))) 10000)");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Symbol(String::from("done")))));
+        assert_eq!(eval(&expr("(cond ((car '(5))))"), &env), Ok(Object::Atom(Atom::Integer(5))));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let env = Environment::new();
+        env.set_max_depth(200);
+        eval(&expr("(defun deep (n) (cond ((<= n 0) 0) (t (+ 1 (deep (- n 1))))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(deep 10)"), &env), Ok(Object::Atom(Atom::Integer(10))));
+        assert_eq!(eval(&expr("(deep 1000)"), &env), Err(LispError::StackDepthExceeded(200)));
+        assert_eq!(eval(&expr("(deep 20)"), &env), Ok(Object::Atom(Atom::Integer(20))));
+        assert_eq!(eval(&expr("(count-down 1000)"), &env), Err(LispError::UndefinedFunction(String::from("count-down"))));
+        eval(&expr("(defun count-down (n) (cond ((<= n 0) 'done) (t (count-down (- n 1)))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(count-down 1000)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("done")))));
+
+        // The default limit is reached before a thread's default stack overflows
+        let result = std::thread::Builder::new().stack_size(2 << 20).spawn(|| {
+            let env = Environment::new();
+            eval(&expr("(defun deep (n) (cond ((<= n 0) 0) (t (+ 1 (deep (- n 1))))))"), &env).unwrap();
+            eval(&expr("(deep 100000)"), &env).unwrap_err().to_string()
+        });
+        assert_eq!(result.unwrap().join().unwrap(), LispError::StackDepthExceeded(DEFAULT_MAX_DEPTH).to_string());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_let() {
+        let env = Environment::new();
+        eval(&expr("(setq x 1)"), &env).unwrap();
+        assert_eq!(eval(&expr("(let ((x 2) (y x)) (list x y))"), &env), Ok(expr("(2 1)")));
+        assert_eq!(eval(&expr("(let* ((x 2) (y x)) (list x y))"), &env), Ok(expr("(2 2)")));
+        assert_eq!(eval(&expr("(let (a (b)) (list a b))"), &env), Ok(expr("(nil nil)")));
+        assert_eq!(eval(&expr("(let () 5)"), &env), Ok(Object::Atom(Atom::Integer(5))));
+        assert_eq!(eval(&expr("(let ((x 5)) (setq x 6) x)"), &env), Ok(Object::Atom(Atom::Integer(6))));
+        assert_eq!(eval(&expr("x"), &env), Ok(Object::Atom(Atom::Integer(1))));
+        assert_eq!(eval(&expr("(let ((z 1)) z)"), &env), Ok(Object::Atom(Atom::Integer(1))));
+        assert_eq!(eval(&expr("z"), &env), Err(LispError::UnboundVariable(String::from("z"))));
+        assert!(matches!(eval(&expr("(let ((1 2)) 3)"), &env), Err(LispError::WrongType { expected: "symbol", .. })));
+    }
+
+    #[test]
+    fn test_flet_and_labels() {
+        let env = Environment::new();
+        eval(&expr("(defun f (x) (list 'global x))"), &env).unwrap();
+        assert_eq!(eval(&expr("(flet ((f (x) (list 'local x))) (f 1))"), &env), Ok(expr("(local 1)")));
+        assert_eq!(eval(&expr("(f 1)"), &env), Ok(expr("(global 1)")));
+        // flet functions can't see each other, so the inner call reaches the global f
+        assert_eq!(eval(&expr("(flet ((f (x) (f (+ x 1)))) (f 1))"), &env), Ok(expr("(global 2)")));
+
+        let test_expr = expr("(labels ((fact (n) (cond ((<= n 1) 1) (t (* n (fact (- n 1))))))) (fact 5))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Integer(120))));
+        assert_eq!(eval(&expr("(fact 5)"), &env), Err(LispError::UndefinedFunction(String::from("fact"))));
+
+        let test_expr = expr("(let ((n 10)) (flet ((add-n (x) (+ x n))) (funcall #'add-n 5)))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Integer(15))));
+    }
+
+    #[test]
+    fn test_if() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(if (< 1 2) 'yes undefined)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("yes")))));
+        assert_eq!(eval(&expr("(if nil undefined 'no)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("no")))));
+        assert_eq!(eval(&expr("(if nil undefined)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert!(matches!(eval(&expr("(if t)"), &env), Err(LispError::ArityMismatch { min: 2, max: Some(3), .. })));
+    }
+
+    #[test]
+    fn test_when_unless() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(when t (setq x 1) (+ x 1))"), &env), Ok(Object::Atom(Atom::Integer(2))));
+        assert_eq!(eval(&expr("(when nil undefined)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(unless nil 1 2)"), &env), Ok(Object::Atom(Atom::Integer(2))));
+        assert_eq!(eval(&expr("(unless t undefined)"), &env), Ok(Object::Atom(Atom::Nil)));
+    }
+
+    #[test]
+    fn test_or_not() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(or nil (car '(5)) undefined)"), &env), Ok(Object::Atom(Atom::Integer(5))));
+        assert_eq!(eval(&expr("(or nil nil)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(or)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(not nil)"), &env), Ok(Object::Atom(Atom::T)));
+        assert_eq!(eval(&expr("(not 5)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(funcall #'not nil)"), &env), Ok(Object::Atom(Atom::T)));
+    }
+
+    #[test]
+    fn test_progn() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(progn (setq x 1) (setq x (+ x 1)) x)"), &env), Ok(Object::Atom(Atom::Integer(2))));
+        assert_eq!(eval(&expr("(progn)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(prog1 x (setq x 10))"), &env), Ok(Object::Atom(Atom::Integer(2))));
+        assert_eq!(eval(&expr("(prog2 (setq x 1) x (setq x 3))"), &env), Ok(Object::Atom(Atom::Integer(1))));
+        assert_eq!(eval(&expr("x"), &env), Ok(Object::Atom(Atom::Integer(3))));
+        assert!(matches!(eval(&expr("(prog2 1)"), &env), Err(LispError::ArityMismatch { min: 2, .. })));
+    }
+
+    #[test]
+    fn test_dotimes_dolist() {
+        let env = Environment::new();
+        eval(&expr("(setq total 0)"), &env).unwrap();
+        assert_eq!(eval(&expr("(dotimes (i 5 i) (setq total (+ total i)))"), &env), Ok(Object::Atom(Atom::Integer(5))));
+        assert_eq!(eval(&expr("total"), &env), Ok(Object::Atom(Atom::Integer(10))));
+        assert_eq!(eval(&expr("(dotimes (i 0) undefined)"), &env), Ok(Object::Atom(Atom::Nil)));
+
+        eval(&expr("(setq seen nil)"), &env).unwrap();
+        assert_eq!(eval(&expr("(dolist (x '(a b c) seen) (setq seen (cons x seen)))"), &env), Ok(expr("(c b a)")));
+        assert_eq!(eval(&expr("(dolist (x nil) undefined)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("i"), &env), Err(LispError::UnboundVariable(String::from("i"))));
+    }
+
+    #[test]
+    fn test_do() {
+        let env = Environment::new();
+        let test_expr = expr("(do ((i 0 (+ i 1)) (acc nil (cons i acc))) ((>= i 3) acc))");
+        assert_eq!(eval(&test_expr, &env), Ok(expr("(2 1 0)")));
+        // do steps in parallel while do* steps sequentially
+        let test_expr = expr("(do ((a 1 (+ a 1)) (b 1 a)) ((>= a 3) (list a b)))");
+        assert_eq!(eval(&test_expr, &env), Ok(expr("(3 2)")));
+        let test_expr = expr("(do* ((a 1 (+ a 1)) (b a a)) ((>= a 3) (list a b)))");
+        assert_eq!(eval(&test_expr, &env), Ok(expr("(3 3)")));
+    }
+
+    #[test]
+    fn test_loop() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(loop for x in '(1 2 3) collect (* x x))"), &env), Ok(expr("(1 4 9)")));
+        assert_eq!(eval(&expr("(loop for i from 1 to 100 sum i)"), &env), Ok(Object::Atom(Atom::Integer(5050))));
+        assert_eq!(eval(&expr("(loop for i from 0 below 10 by 3 collect i)"), &env), Ok(expr("(0 3 6 9)")));
+        let test_expr = expr("(loop for x in '(1 2 3 4) for i from 0 while (< x 3) collect (list i x))");
+        assert_eq!(eval(&test_expr, &env), Ok(expr("((0 1) (1 2))")));
+        let test_expr = expr("(loop for i from 1 do (setq last i) until (>= i 4) finally (setq done t))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(list last done)"), &env), Ok(expr("(4 t)")));
+        assert!(matches!(eval(&expr("(loop for x on '(1))"), &env), Err(LispError::WrongType { .. })));
+    }
+
+    #[test]
+    fn test_block_return_from() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(block outer (block inner (return-from outer 1) 2) 3)"), &env), Ok(Object::Atom(Atom::Integer(1))));
+        assert_eq!(eval(&expr("(block b (return-from b) 3)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(dolist (x '(1 2 3 4)) (when (> x 2) (return x)))"), &env), Ok(Object::Atom(Atom::Integer(3))));
+        assert_eq!(eval(&expr("(let ((i 0)) (loop (setq i (+ i 1)) (when (>= i 5) (return i))))"), &env), Ok(Object::Atom(Atom::Integer(5))));
+
+        eval(&expr("(defun find-first (l) (dolist (x l) (when (> x 10) (return-from find-first x))) 'none)"), &env).unwrap();
+        assert_eq!(eval(&expr("(find-first '(1 20 30))"), &env), Ok(Object::Atom(Atom::Integer(20))));
+        assert_eq!(eval(&expr("(find-first '(1 2))"), &env), Ok(Object::Atom(Atom::Symbol(String::from("none")))));
+
+        // Every named function has a block, even when `return-from` comes from a macro
+        eval(&expr("(defmacro bail (name) `(return-from ,name 'bailed))"), &env).unwrap();
+        eval(&expr("(defun early () (bail early) 'late)"), &env).unwrap();
+        assert_eq!(eval(&expr("(early)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("bailed")))));
+        // An inner call's `return-from` only leaves that call
+        eval(&expr("(defun depth (n) (if (<= n 0) (return-from depth 0) (+ 1 (depth (- n 1)))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(depth 5)"), &env), Ok(Object::Atom(Atom::Integer(5))));
+        // `return-from` leaves the block it is written in, not the nearest running one
+        eval(&expr("(defun my-each (f l) (dolist (x l) (funcall f x)) 'finished)"), &env).unwrap();
+        let test_expr = expr("(dolist (y '(1 2 3)) (my-each (lambda (x) (return 'early)) '(a b)))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Symbol(String::from("early")))));
+        eval(&expr("(defun k (f n) (if (<= n 0) (funcall f) (list (k f (- n 1)))))"), &env).unwrap();
+        eval(&expr("(defun start () (k (lambda () (return-from k 'escaped)) 2))"), &env).unwrap();
+        let error = eval(&expr("(start)"), &env).unwrap_err();
+        assert_eq!(error.to_string(), "No block named k to return from");
+
+        // Blocks keep their last form a tail call
+        eval(&expr("(defun count-down (n) (if (<= n 0) (return-from count-down 'ok) (count-down (- n 1))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(count-down 100000)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("ok")))));
+        eval(&expr("(defun spin (n) (block b (if (<= n 0) (return-from b n) (spin (- n 1)))))"), &env).unwrap();
+        assert_eq!(eval(&expr("(spin 100000)"), &env), Ok(Object::Atom(Atom::Integer(0))));
+
+        let error = eval(&expr("(return-from nowhere 5)"), &env).unwrap_err();
+        assert_eq!(error.to_string(), "No block named nowhere to return from");
+    }
+
+    #[test]
+    fn test_catch_throw() {
+        let env = Environment::new();
+        eval(&expr("(defun search (l) (dolist (x l) (when (eq x 'b) (throw 'found x))) nil)"), &env).unwrap();
+        assert_eq!(eval(&expr("(catch 'found (search '(a b c)) 'missing)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("b")))));
+        assert_eq!(eval(&expr("(catch 'found (search '(a c)) 'missing)"), &env), Ok(Object::Atom(Atom::Symbol(String::from("missing")))));
+        assert_eq!(eval(&expr("(catch 'outer (catch 'inner (throw 'outer 1)) 2)"), &env), Ok(Object::Atom(Atom::Integer(1))));
+        let error = eval(&expr("(throw 'nobody 5)"), &env).unwrap_err();
+        assert_eq!(error.to_string(), "No catch for tag nobody");
+    }
+
+    #[test]
+    fn test_unwind_protect() {
+        let env = Environment::new();
+        eval(&expr("(setq log nil)"), &env).unwrap();
+        let test_expr = expr("(catch 'done (unwind-protect (throw 'done 1) (setq log (cons 'thrown log))))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Integer(1))));
+        assert_eq!(eval(&expr("(unwind-protect undefined (setq log (cons 'error log)))"), &env), Err(LispError::UnboundVariable(String::from("undefined"))));
+        assert_eq!(eval(&expr("(unwind-protect 5 (setq log (cons 'normal log)))"), &env), Ok(Object::Atom(Atom::Integer(5))));
+        assert_eq!(eval(&expr("log"), &env), Ok(expr("(normal error thrown)")));
+    }
+
+    #[test]
+    fn test_handler_case() {
+        let env = Environment::new();
+        let test_expr = expr("(handler-case (error \"bad thing\") (error (e) (list 'caught (condition-message e))))");
+        assert_eq!(eval(&test_expr, &env), Ok(expr("(caught \"bad thing\")")));
+        let test_expr = expr("(handler-case (/ 1 0) (type-error () 'type) (arithmetic-error (e) (condition-type e)))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::Symbol(String::from("division-by-zero")))));
+        let test_expr = expr("(handler-case (+ 1 'a) (type-error (e) (condition-message e)))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::String(String::from("Wrong type: expected number, got a")))));
+        assert_eq!(eval(&expr("(handler-case (+ 1 2) (error () 'failed))"), &env), Ok(Object::Atom(Atom::Integer(3))));
+        assert_eq!(eval(&expr("(handler-case undefined (unbound-variable () 'unbound))"), &env), Ok(Object::Atom(Atom::Symbol(String::from("unbound")))));
+
+        let test_expr = expr("(handler-case (error 'my-error \"custom\") (my-error (e) (condition-message e)))");
+        assert_eq!(eval(&test_expr, &env), Ok(Object::Atom(Atom::String(String::from("custom")))));
+        let error = eval(&expr("(handler-case (error 'my-error \"custom\") (warning () 'no))"), &env).unwrap_err();
+        assert_eq!(error.to_string(), "my-error: custom");
+        // Non-local exits pass through handlers
+        assert_eq!(eval(&expr("(catch 'tag (handler-case (throw 'tag 1) (condition () 2)))"), &env), Ok(Object::Atom(Atom::Integer(1))));
+    }
+
+    #[test]
+    fn test_signal_and_warn() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(signal 'note \"nobody listens\")"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(handler-case (progn (signal 'note) 'finished) (note () 'handled))"), &env), Ok(Object::Atom(Atom::Symbol(String::from("handled")))));
+        assert_eq!(eval(&expr("(handler-case (progn (warn \"careful\") 'finished) (error () 'error))"), &env), Ok(Object::Atom(Atom::Symbol(String::from("finished")))));
+        assert_eq!(eval(&expr("(handler-case (warn \"careful\") (warning (w) (condition-message w)))"), &env), Ok(Object::Atom(Atom::String(String::from("careful")))));
+    }
+
+    #[test]
+    fn test_ignore_errors() {
+        let env = Environment::new();
+        assert_eq!(eval(&expr("(ignore-errors (car 5))"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(ignore-errors 1 2)"), &env), Ok(Object::Atom(Atom::Integer(2))));
+        eval(&expr("(defun deep (n) (+ 1 (deep n)))"), &env).unwrap();
+        env.set_max_depth(100);
+        assert_eq!(eval(&expr("(ignore-errors (deep 1))"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(deep 1)"), &env), Err(LispError::StackDepthExceeded(100)));
+    }
+
+    #[test]
+    fn test_optional_parameters() {
+        let env = Environment::new();
+        eval(&expr("(defun greet (name &optional (greeting \"hello\" greeting-p) punctuation) (list greeting name punctuation greeting-p))"), &env).unwrap();
+        assert_eq!(eval(&expr("(greet 'bob)"), &env), Ok(expr("(\"hello\" bob nil nil)")));
+        assert_eq!(eval(&expr("(greet 'bob \"hi\" '!)"), &env), Ok(expr("(\"hi\" bob ! t)")));
+        eval(&expr("(defun range (a &optional (b (+ a 10))) (list a b))"), &env).unwrap();
+        assert_eq!(eval(&expr("(range 1)"), &env), Ok(expr("(1 11)")));
+        assert_eq!(eval(&expr("(greet)"), &env), Err(LispError::ArityMismatch { name: String::from("greet"), min: 1, max: Some(3), found: 0 }));
+        assert_eq!(eval(&expr("(greet 1 2 3 4)"), &env), Err(LispError::ArityMismatch { name: String::from("greet"), min: 1, max: Some(3), found: 4 }));
+    }
+
+    #[test]
+    fn test_rest_parameters() {
+        let env = Environment::new();
+        eval(&expr("(defun tail (first &rest others) (list first others))"), &env).unwrap();
+        assert_eq!(eval(&expr("(tail 1 2 3)"), &env), Ok(expr("(1 (2 3))")));
+        assert_eq!(eval(&expr("(tail 1)"), &env), Ok(expr("(1 nil)")));
+        assert_eq!(eval(&expr("(tail)"), &env), Err(LispError::ArityMismatch { name: String::from("tail"), min: 1, max: None, found: 0 }));
+
+        eval(&expr("(defmacro my-when (test &body body) `(cond (,test ,@body)))"), &env).unwrap();
+        assert_eq!(eval(&expr("(my-when t 1 2)"), &env), Ok(Object::Atom(Atom::Integer(2))));
+
+        eval(&expr("(load \"builtin.l\")"), &env).unwrap();
+        assert_eq!(eval(&expr("(append '(1) '(2 3) nil '(4))"), &env), Ok(expr("(1 2 3 4)")));
+        assert_eq!(eval(&expr("(append)"), &env), Ok(Object::Atom(Atom::Nil)));
+    }
+
+    #[test]
+    fn test_key_parameters() {
+        let env = Environment::new();
+        eval(&expr("(defun make-point (&key (x 0) (y x y-p)) (list x y y-p))"), &env).unwrap();
+        assert_eq!(eval(&expr("(make-point)"), &env), Ok(expr("(0 0 nil)")));
+        assert_eq!(eval(&expr("(make-point :y 2 :x 1)"), &env), Ok(expr("(1 2 t)")));
+        assert_eq!(eval(&expr("(make-point :x 5)"), &env), Ok(expr("(5 5 nil)")));
+        assert!(matches!(eval(&expr("(make-point :z 1)"), &env), Err(LispError::WrongType { expected: "known keyword", .. })));
+        assert!(matches!(eval(&expr("(make-point :x)"), &env), Err(LispError::WrongType { expected: "keyword arguments", .. })));
+        assert_eq!(eval(&expr(":x"), &env), Ok(expr(":x")));
+    }
+
+    #[test]
+    fn test_shared_conses() {
+        let env = Environment::new();
+        eval(&expr("(setq x (list 1 2 3))"), &env).unwrap();
+        eval(&expr("(setq y (cdr x))"), &env).unwrap();
+        assert_eq!(eval(&expr("(eq (cdr x) y)"), &env), Ok(Object::Atom(Atom::T)));
+        assert_eq!(eval(&expr("(eq (list 1) (list 1))"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(equal (list 1) (list 1))"), &env), Ok(Object::Atom(Atom::T)));
+
+        eval(&expr("(rplaca y 20)"), &env).unwrap();
+        assert_eq!(eval(&expr("x"), &env), Ok(expr("(1 20 3)")));
+        assert_eq!(eval(&expr("(rplacd (cdr y) '(4))"), &env), Ok(expr("(3 4)")));
+        assert_eq!(eval(&expr("x"), &env), Ok(expr("(1 20 3 4)")));
+        assert!(matches!(eval(&expr("(rplaca nil 1)"), &env), Err(LispError::WrongType { expected: "cons", .. })));
+
+        assert_eq!(eval(&expr("(setf (car x) 10)"), &env), Ok(Object::Atom(Atom::Integer(10))));
+        eval(&expr("(setf (cdr y) nil)"), &env).unwrap();
+        assert_eq!(eval(&expr("x"), &env), Ok(expr("(10 20)")));
+        eval(&expr("(setf z 5)"), &env).unwrap();
+        assert_eq!(eval(&expr("z"), &env), Ok(Object::Atom(Atom::Integer(5))));
+        assert!(matches!(eval(&expr("(setf (length x) 1)"), &env), Err(LispError::WrongType { expected: "place", .. })));
+
+        assert_eq!(eval(&expr("(nconc nil x (list 30) nil (list 40))"), &env), Ok(expr("(10 20 30 40)")));
+        assert_eq!(eval(&expr("x"), &env), Ok(expr("(10 20 30 40)")));
+        assert_eq!(eval(&expr("(nconc)"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(nconc (list 1) 2)"), &env), Ok(expr("(1 . 2)")));
+        assert_eq!(eval(&expr("(nconc nil 'a)"), &env), Ok(expr("a")));
+        // A bad argument is reported before any list is changed
+        assert!(matches!(eval(&expr("(nconc x 2 (list 3))"), &env), Err(LispError::WrongType { expected: "cons", .. })));
+        assert_eq!(eval(&expr("x"), &env), Ok(expr("(10 20 30 40)")));
+
+        // Long lists are compared and freed without deep recursion
+        eval(&expr("(setq long nil)"), &env).unwrap();
+        eval(&expr("(dotimes (i 50000) (setq long (cons i long)))"), &env).unwrap();
+        assert_eq!(eval(&expr("(equal long (cons 49999 (cdr long)))"), &env), Ok(Object::Atom(Atom::T)));
+        eval(&expr("(setq long nil)"), &env).unwrap();
+    }
+
+    #[test]
+    fn test_circular_lists() {
+        let env = Environment::new();
+        let print = |source: &str| fn_print_helper(&eval(&expr(source), &env).unwrap());
+        eval(&expr("(setq a (list 1 2))"), &env).unwrap();
+        assert_eq!(print("(setf (cdr (cdr a)) a)"), "#1=(1 2 . #1#)");
+        assert_eq!(print("(list a a)"), "(#1=(1 2 . #1#) #1#)");
+        assert_eq!(print("(let ((b (list 1 2 3))) (setf (cdr (cdr (cdr b))) (cdr b)) b)"), "(1 . #1=(2 3 . #1#))");
+        assert_eq!(print("(let ((c (list 1 2))) (setf (car (cdr c)) c) c)"), "#1=(1 #1#)");
+        // Shared structure that doesn't loop prints as usual
+        assert_eq!(print("(let ((d (list 1))) (list d d))"), "((1) (1))");
+
+        assert_eq!(eval(&expr("(equal a (list 1 2))"), &env), Ok(Object::Atom(Atom::Nil)));
+        assert_eq!(eval(&expr("(let ((c (list 1 2 1 2))) (setf (cdr (cdr (cdr (cdr c)))) c) (equal a c))"), &env), Ok(Object::Atom(Atom::T)));
+        assert!(matches!(eval(&expr("(dolist (x a) x)"), &env), Err(LispError::WrongType { expected: "list", .. })));
+        assert!(matches!(eval(&expr("(nconc a 3)"), &env), Err(LispError::WrongType { expected: "proper list", .. })));
+        assert_eq!(print("(car (cdr (cdr a)))"), "1");
+    }
+}
@@ -1,37 +1,74 @@
-use crate::functions::fn_print;
-use crate::functions::fn_eval_multiple;
-use crate::interpreter::eval;
-use crate::tokenizer::{Token};
+use crate::functions::{fn_load, fresh_line};
+use crate::parser::Object;
+use crate::tokenizer::Atom;
 
+mod error;
 mod parser;
 mod tokenizer;
 mod interpreter;
 mod functions;
+mod gc;
+mod number;
+mod pprint;
+mod repl;
+
+/// The limit on how deeply evaluation may nest without `--max-depth`
+const MAX_DEPTH: usize = 10000;
+
+/// Native stack used outside of evaluation: loading, the REPL and printing results
+const BASE_STACK_SIZE: usize = 64 << 20;
+
+/// Stack size of the interpreter thread, large enough that the `--max-depth` limit is reached
+/// before the native stack overflows
+fn stack_size(max_depth: usize) -> Option<usize> {
+    max_depth.checked_mul(interpreter::STACK_PER_DEPTH)?.checked_add(BASE_STACK_SIZE)
+}
 
 fn main() {
-    let mut stack = interpreter::Stack::new();
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut max_depth = MAX_DEPTH;
+    if let Some(index) = args.iter().position(|arg| arg == "--max-depth") {
+        let value = args.get(index + 1).and_then(|value| value.parse().ok());
+        let Some(value) = value else {
+            eprintln!("Error: --max-depth expects a number");
+            std::process::exit(1);
+        };
+        max_depth = value;
+        args.drain(index..index + 2);
+    }
+
+    // The stack is reserved up front, so a limit needing more than the system will map fails here
+    let interpreter = stack_size(max_depth).and_then(|size| {
+        std::thread::Builder::new()
+            .stack_size(size)
+            .spawn(move || run(args.first().cloned(), max_depth))
+            .ok()
+    });
+    let Some(interpreter) = interpreter else {
+        eprintln!("Error: --max-depth {} needs more stack than is available", max_depth);
+        std::process::exit(1);
+    };
+    if !interpreter.join().unwrap() {
+        std::process::exit(1);
+    }
+}
+
+/// Runs a script file, or the interactive mode without one. Returns whether it succeeded.
+fn run(file_path: Option<String>, max_depth: usize) -> bool {
+    let env = interpreter::Environment::new();
+    env.set_max_depth(max_depth);
 
-    if std::env::args().len() > 1 {
+    if let Some(file_path) = file_path {
         // Run using file
-        let file_path = std::env::args().nth(1).unwrap();
-        let file_content = std::fs::read_to_string(file_path).unwrap();
-        let tokens = tokenizer::tokenize_expr(&file_content);
-        let tokens = vec![]
-            .into_iter()
-            .chain(vec![Token::LParen])
-            .chain(tokens.into_iter())
-            .chain(vec![Token::RParen].into_iter())
-            .collect::<Vec<_>>();
-        let parsed = parser::parse(&mut tokens.iter().peekable());
-        fn_eval_multiple(&parsed, &mut stack);
-    } else {
-        // Run interactive mode
-        loop {
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input).unwrap();
-            let tokens = tokenizer::tokenize_expr(&input);
-            let parsed = parser::parse(&mut tokens.iter().peekable());
-            fn_print(&eval(&parsed, &mut stack));
+        let result = fn_load(&Object::Atom(Atom::String(file_path)), &env);
+        fresh_line();
+        if let Err(error) = result {
+            eprintln!("Error: {}", error);
+            return false;
         }
+    } else if let Err(error) = repl::run_repl(&env) {
+        eprintln!("Error: {}", error);
+        return false;
     }
+    true
 }
@@ -0,0 +1,277 @@
+use std::cmp::Ordering;
+
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+
+use crate::error::LispError;
+use crate::parser::Object;
+use crate::tokenizer::{Atom, MyFloat};
+
+/// Numbers are kept in the smallest exact representation that holds them: fixnums
+/// promote to bignums on overflow, and ratios with a denominator of one become integers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Integer(i32),
+    BigInt(BigInt),
+    Ratio(BigRational),
+    Float(f64),
+}
+
+impl From<usize> for Number {
+    fn from(number: usize) -> Number {
+        Number::from_bigint(BigInt::from(number))
+    }
+}
+
+impl Number {
+    pub fn from_object(object: &Object) -> Result<Number, LispError> {
+        match object {
+            Object::Atom(Atom::Integer(number)) => Ok(Number::Integer(*number)),
+            Object::Atom(Atom::BigInt(number)) => Ok(Number::BigInt(number.clone())),
+            Object::Atom(Atom::Ratio(ratio)) => Ok(Number::Ratio(ratio.clone())),
+            Object::Atom(Atom::Float(MyFloat(number))) => Ok(Number::Float(*number)),
+            _ => Err(LispError::WrongType { expected: "number", found: object.clone() })
+        }
+    }
+
+    pub fn into_atom(self) -> Atom {
+        match self {
+            Number::Integer(number) => Atom::Integer(number),
+            Number::BigInt(number) => Atom::BigInt(number),
+            Number::Ratio(ratio) => Atom::Ratio(ratio),
+            Number::Float(number) => Atom::Float(MyFloat(number)),
+        }
+    }
+
+    pub fn into_object(self) -> Object {
+        Object::Atom(self.into_atom())
+    }
+
+    /// Demotes a bignum to a fixnum when it fits
+    fn from_bigint(number: BigInt) -> Number {
+        match number.to_i32() {
+            Some(small) => Number::Integer(small),
+            None => Number::BigInt(number),
+        }
+    }
+
+    /// Collapses a ratio with a denominator of one to an integer
+    fn from_ratio(ratio: BigRational) -> Number {
+        if ratio.is_integer() {
+            Number::from_bigint(ratio.to_integer())
+        } else {
+            Number::Ratio(ratio)
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(number) => *number as f64,
+            Number::BigInt(number) => number.to_f64().unwrap_or(f64::NAN),
+            Number::Ratio(ratio) => ratio.to_f64().unwrap_or(f64::NAN),
+            Number::Float(number) => *number,
+        }
+    }
+
+    fn as_bigint(&self) -> Option<BigInt> {
+        match self {
+            Number::Integer(number) => Some(BigInt::from(*number)),
+            Number::BigInt(number) => Some(number.clone()),
+            _ => None,
+        }
+    }
+
+    /// The exact value of a rational number, or `None` for floats
+    fn as_rational(&self) -> Option<BigRational> {
+        match self {
+            Number::Ratio(ratio) => Some(ratio.clone()),
+            Number::Float(_) => None,
+            _ => self.as_bigint().map(BigRational::from_integer),
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Number::Integer(number) => *number == 0,
+            Number::BigInt(number) => number.is_zero(),
+            Number::Ratio(ratio) => ratio.is_zero(),
+            Number::Float(number) => *number == 0.0,
+        }
+    }
+
+    /// Applies an operation using fixnum arithmetic if it doesn't overflow, bignums for
+    /// other integers, exact ratios for rationals and floating point otherwise
+    fn combine(
+        &self,
+        other: &Number,
+        fixnum: fn(i32, i32) -> Option<i32>,
+        rational: fn(BigRational, BigRational) -> BigRational,
+        float: fn(f64, f64) -> f64,
+    ) -> Number {
+        if let (Number::Integer(a), Number::Integer(b)) = (self, other) {
+            if let Some(result) = fixnum(*a, *b) {
+                return Number::Integer(result);
+            }
+        }
+        match (self.as_rational(), other.as_rational()) {
+            (Some(a), Some(b)) => Number::from_ratio(rational(a, b)),
+            _ => Number::Float(float(self.to_f64(), other.to_f64())),
+        }
+    }
+
+    pub fn add(&self, other: &Number) -> Number {
+        self.combine(other, i32::checked_add, |a, b| a + b, |a, b| a + b)
+    }
+
+    pub fn sub(&self, other: &Number) -> Number {
+        self.combine(other, i32::checked_sub, |a, b| a - b, |a, b| a - b)
+    }
+
+    pub fn mul(&self, other: &Number) -> Number {
+        self.combine(other, i32::checked_mul, |a, b| a * b, |a, b| a * b)
+    }
+
+    /// Divides, producing a ratio rather than truncating when integers don't divide evenly
+    pub fn div(&self, other: &Number) -> Result<Number, LispError> {
+        if other.is_zero() {
+            return Err(LispError::DivisionByZero);
+        }
+        match (self.as_rational(), other.as_rational()) {
+            (Some(a), Some(b)) => Ok(Number::from_ratio(a / b)),
+            _ => Ok(Number::Float(self.to_f64() / other.to_f64())),
+        }
+    }
+
+    /// The largest integer not greater than this number
+    pub fn floor(&self) -> Result<Number, LispError> {
+        match self {
+            Number::Integer(_) | Number::BigInt(_) => Ok(self.clone()),
+            Number::Ratio(ratio) => Ok(Number::from_bigint(ratio.numer().div_floor(ratio.denom()))),
+            Number::Float(number) => BigInt::from_f64(number.floor())
+                .map(Number::from_bigint)
+                .ok_or(LispError::Overflow),
+        }
+    }
+
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self.as_rational(), other.as_rational()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => self.to_f64().partial_cmp(&other.to_f64()),
+        }
+    }
+}
+
+fn is_integer_literal(w: &str) -> bool {
+    let digits = w.strip_prefix(['+', '-']).unwrap_or(w);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Writes a float so that `parse_number` reads back the same value. Infinities and NaN, which
+/// have no digits to write, are written `+inf.0`, `-inf.0` and `+nan.0`.
+pub fn format_float(number: f64) -> String {
+    if number.is_nan() {
+        String::from("+nan.0")
+    } else if number.is_infinite() {
+        String::from(if number > 0.0 { "+inf.0" } else { "-inf.0" })
+    } else {
+        format!("{:?}", number)
+    }
+}
+
+/// Reads an integer (`42`, `123456789012345678901234567890`), ratio (`3/4`) or float (`2.5`,
+/// `+inf.0`, `+nan.0`) literal. Ratios with a zero denominator are not numbers.
+pub fn parse_number(w: &str) -> Option<Number> {
+    for special in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+        if w.eq_ignore_ascii_case(&format_float(special)) {
+            return Some(Number::Float(special));
+        }
+    }
+    if is_integer_literal(w) {
+        return w.parse::<BigInt>().ok().map(Number::from_bigint);
+    }
+    if let Some((numer, denom)) = w.split_once('/') {
+        if is_integer_literal(numer) && denom.bytes().all(|b| b.is_ascii_digit()) {
+            let numer = numer.parse::<BigInt>().ok()?;
+            let denom = denom.parse::<BigInt>().ok()?;
+            if denom.is_zero() {
+                return None;
+            }
+            return Some(Number::from_ratio(BigRational::new(numer, denom)));
+        }
+        return None;
+    }
+    let is_float_syntax = w.bytes().any(|b| b.is_ascii_digit())
+        && w.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'+' | b'-' | b'.' | b'e' | b'E'));
+    if is_float_syntax {
+        return w.parse::<f64>().ok().map(Number::Float);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratio(numer: i32, denom: i32) -> Number {
+        Number::Ratio(BigRational::new(BigInt::from(numer), BigInt::from(denom)))
+    }
+
+    #[test]
+    fn integer_arithmetic_stays_exact() {
+        assert_eq!(Number::Integer(2).add(&Number::Integer(3)), Number::Integer(5));
+        assert_eq!(Number::Integer(6).div(&Number::Integer(3)), Ok(Number::Integer(2)));
+        assert_eq!(Number::Integer(2).mul(&Number::Float(1.5)), Number::Float(3.0));
+    }
+
+    #[test]
+    fn division_produces_ratios() {
+        let half = Number::Integer(1).div(&Number::Integer(2)).unwrap();
+        assert_eq!(half, ratio(1, 2));
+        assert_eq!(Number::Integer(-2).div(&Number::Integer(-4)), Ok(half.clone()));
+        assert_eq!(half.add(&half), Number::Integer(1));
+        assert_eq!(Number::Integer(3).div(&Number::Integer(-6)), Ok(ratio(-1, 2)));
+        assert_eq!(Number::Integer(1).div(&Number::Integer(0)), Err(LispError::DivisionByZero));
+    }
+
+    #[test]
+    fn fixnum_overflow_promotes_to_bignum() {
+        let big = Number::Integer(i32::MAX).add(&Number::Integer(1));
+        assert_eq!(big, Number::BigInt(BigInt::from(i32::MAX as i64 + 1)));
+        assert_eq!(big.sub(&Number::Integer(1)), Number::Integer(i32::MAX));
+        assert_eq!(Number::Integer(i32::MIN).mul(&Number::Integer(-1)), Number::BigInt(BigInt::from(2147483648_i64)));
+    }
+
+    #[test]
+    fn floor_rounds_down() {
+        assert_eq!(Number::Integer(-7).div(&Number::Integer(2)).unwrap().floor(), Ok(Number::Integer(-4)));
+        assert_eq!(Number::Float(3.7).floor(), Ok(Number::Integer(3)));
+        assert_eq!(Number::Float(f64::INFINITY).floor(), Err(LispError::Overflow));
+    }
+
+    #[test]
+    fn compare_mixed() {
+        let third = Number::Integer(1).div(&Number::Integer(3)).unwrap();
+        assert_eq!(third.compare(&Number::Float(0.3)), Some(Ordering::Greater));
+        assert_eq!(third.compare(&ratio(1, 2)), Some(Ordering::Less));
+    }
+
+    #[test]
+    fn parse_literals() {
+        assert_eq!(parse_number("-12"), Some(Number::Integer(-12)));
+        assert_eq!(
+            parse_number("123456789012345678901234567890"),
+            Some(Number::BigInt("123456789012345678901234567890".parse().unwrap()))
+        );
+        assert_eq!(parse_number("3/4"), Some(ratio(3, 4)));
+        assert_eq!(parse_number("4/2"), Some(Number::Integer(2)));
+        assert_eq!(parse_number("2.5"), Some(Number::Float(2.5)));
+        assert_eq!(parse_number("1/0"), None);
+        assert_eq!(parse_number("inf"), None);
+        assert_eq!(parse_number("-inf.0"), Some(Number::Float(f64::NEG_INFINITY)));
+        assert!(matches!(parse_number("+NaN.0"), Some(Number::Float(number)) if number.is_nan()));
+        assert_eq!(parse_number("-"), None);
+        assert_eq!(parse_number("a/b"), None);
+    }
+}
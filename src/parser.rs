@@ -1,93 +1,547 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::iter::Peekable;
+use std::rc::{Rc, Weak};
 use std::slice::Iter;
-use crate::tokenizer::{Atom, Token};
+use crate::error::Condition;
+use crate::gc::{self, Trace};
+use crate::interpreter::Environment;
+use crate::tokenizer::{tokenize_spanned, Atom, Span, Token};
 
-#[derive(Debug, PartialEq, Clone)]
+/// A pair of mutable references. Lists are chains of cells linked through their cdr and
+/// ending in nil, and cells are shared rather than copied.
 pub struct ConsCell {
-    pub car: Object,
-    pub cdr: Object,
+    car: RefCell<Object>,
+    cdr: RefCell<Object>,
 }
 
 impl ConsCell {
     pub fn new(car: Object, cdr: Object) -> Self {
         Self {
-            car,
-            cdr,
+            car: RefCell::new(car),
+            cdr: RefCell::new(cdr),
+        }
+    }
+
+    /// Empties the car and cdr, adding the conses they held to `pending`
+    fn unlink(&self, pending: &mut Vec<Rc<ConsCell>>) {
+        for field in [&self.car, &self.cdr] {
+            if let Object::Cons(cell) = field.replace(Object::Atom(Atom::Nil)) {
+                pending.push(cell);
+            }
+        }
+    }
+
+    pub fn car(&self) -> Object {
+        self.car.borrow().clone()
+    }
+
+    pub fn cdr(&self) -> Object {
+        self.cdr.borrow().clone()
+    }
+
+    pub fn set_car(&self, car: Object) {
+        *self.car.borrow_mut() = car;
+    }
+
+    pub fn set_cdr(&self, cdr: Object) {
+        *self.cdr.borrow_mut() = cdr;
+    }
+
+    /// The last cell of the list starting at `cell`, or `None` when the list is circular
+    pub fn last(cell: &Rc<ConsCell>) -> Option<Rc<ConsCell>> {
+        let mut last = Rc::clone(cell);
+        let mut slow = Rc::clone(cell);
+        let mut steps = 0;
+        while let Object::Cons(next) = last.cdr() {
+            last = next;
+            steps += 1;
+            // `slow` moves at half the speed, so it meets `last` if the list loops
+            if steps % 2 == 0 {
+                let Object::Cons(next) = slow.cdr() else { unreachable!() };
+                slow = next;
+                if Rc::ptr_eq(&slow, &last) {
+                    return None;
+                }
+            }
+        }
+        Some(last)
+    }
+}
+
+/// Cells compare structurally, walking the cdr chain in a loop so long lists don't
+/// overflow the stack
+impl PartialEq for ConsCell {
+    fn eq(&self, other: &Self) -> bool {
+        cells_equal(self, other, &mut HashSet::new())
+    }
+}
+
+/// Compares two cells, taking the pairs of cells in `assumed` to be equal. A pair is assumed
+/// equal once its comparison starts, so circular structure is compared in finite time.
+fn cells_equal(a: &ConsCell, b: &ConsCell, assumed: &mut HashSet<(usize, usize)>) -> bool {
+    if !assumed.insert((a as *const ConsCell as usize, b as *const ConsCell as usize)) {
+        return true;
+    }
+    if !objects_equal(&a.car.borrow(), &b.car.borrow(), assumed) {
+        return false;
+    }
+    let mut rest = (a.cdr(), b.cdr());
+    loop {
+        match &rest {
+            (Object::Cons(x), Object::Cons(y)) => {
+                if Rc::ptr_eq(x, y) || !assumed.insert((gc::address(x), gc::address(y))) {
+                    return true;
+                }
+                if !objects_equal(&x.car.borrow(), &y.car.borrow(), assumed) {
+                    return false;
+                }
+                let next = (x.cdr(), y.cdr());
+                rest = next;
+            }
+            (a, b) => return a == b,
+        }
+    }
+}
+
+fn objects_equal(a: &Object, b: &Object, assumed: &mut HashSet<(usize, usize)>) -> bool {
+    match (a, b) {
+        (Object::Cons(x), Object::Cons(y)) => Rc::ptr_eq(x, y) || cells_equal(x, y, assumed),
+        _ => a == b,
+    }
+}
+
+/// Takes apart the cells this one alone owns, cars as well as cdrs, from a worklist, so dropping a
+/// long or deeply nested structure doesn't recurse
+impl Drop for ConsCell {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.unlink(&mut pending);
+        while let Some(cell) = pending.pop() {
+            if let Ok(cell) = Rc::try_unwrap(cell) {
+                cell.unlink(&mut pending);
+            }
+        }
+    }
+}
+
+impl Trace for ConsCell {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        gc::trace_object(&self.car.borrow(), visit);
+        gc::trace_object(&self.cdr.borrow(), visit);
+    }
+
+    fn clear(&self) {
+        let car = self.car.replace(Object::Atom(Atom::Nil));
+        let cdr = self.cdr.replace(Object::Atom(Atom::Nil));
+        drop((car, cdr));
+    }
+
+    fn kind(&self) -> &'static str {
+        "cons"
+    }
+}
+
+impl fmt::Debug for ConsCell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({:?} . {:?})", self.car.borrow(), self.cdr.borrow())
+    }
+}
+
+/// A user defined function along with the environment it was created in
+pub struct Lambda {
+    pub name: Option<String>,
+    pub formals: Object,
+    pub body: Vec<Object>,
+    /// The form that defined the function, which locates errors in its body
+    pub source: Object,
+    pub env: Environment,
+}
+
+pub enum Function {
+    Builtin(String),
+    Lambda(Lambda),
+    /// An expander called with unevaluated argument forms, returning the code to evaluate
+    Macro(Lambda),
+}
+
+impl Function {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Function::Builtin(name) => Some(name),
+            Function::Lambda(lambda) | Function::Macro(lambda) => lambda.name.as_deref(),
         }
     }
 }
 
+impl Trace for Function {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        if let Function::Lambda(lambda) | Function::Macro(lambda) = self {
+            gc::trace_object(&lambda.formals, visit);
+            for form in &lambda.body {
+                gc::trace_object(form, visit);
+            }
+            gc::trace_object(&lambda.source, visit);
+            lambda.env.trace(visit);
+        }
+    }
+
+    /// A function can't drop its references, but any cycle through one also passes through
+    /// a cons or frame that can
+    fn clear(&self) {}
+
+    fn kind(&self) -> &'static str {
+        "function"
+    }
+}
+
+/// Functions compare by identity since closures capture their environment, and builtins by name
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Function::Builtin(a), Function::Builtin(b)) => a == b,
+            _ => std::ptr::eq(self, other),
+        }
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Function({})", self.name().unwrap_or("lambda"))
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Object {
     Atom(Atom),
-    List(Vec<Object>),
-    ConsCell(Box<ConsCell>),
-}
-
-
-/// Parses a single expression
-pub fn parse(token_iter: &mut Peekable<Iter<Token>>) -> Object {
-    if let Some(token) = token_iter.next() {
-        match token {
-            Token::LParen => {
-                let mut list = Vec::new();
-                while let Some(token) = token_iter.peek() {
-                    match token {
-                        Token::LParen => {
-                            let inner_list = parse(token_iter);
-                            list.push(inner_list);
-                        }
-                        Token::RParen => {
-                            token_iter.next();
-                            return Object::List(list);
-                        }
-                        Token::Atom(atom) => {
-                            list.push(Object::Atom(atom.clone()));
-                            token_iter.next();
-                        }
-                    }
+    Cons(Rc<ConsCell>),
+    Function(Rc<Function>),
+    Condition(Rc<Condition>),
+}
+
+impl Object {
+    pub fn cons(car: Object, cdr: Object) -> Object {
+        let cell = Rc::new(ConsCell::new(car, cdr));
+        gc::track(&cell);
+        Object::Cons(cell)
+    }
+
+    pub fn function(function: Function) -> Object {
+        let function = Rc::new(function);
+        gc::track(&function);
+        Object::Function(function)
+    }
+
+    /// A proper list of the items, which is nil when there are none
+    pub fn list(items: Vec<Object>) -> Object {
+        Object::dotted_list(items, Object::Atom(Atom::Nil))
+    }
+
+    /// A list of the items whose last cdr is `tail` rather than nil
+    pub fn dotted_list(items: Vec<Object>, tail: Object) -> Object {
+        items.into_iter().rev().fold(tail, |list, item| Object::cons(item, list))
+    }
+
+    /// The elements of a proper list, or `None` for anything else, including circular lists.
+    /// Nil is the empty list.
+    pub fn to_vec(&self) -> Option<Vec<Object>> {
+        let mut items = Vec::new();
+        let mut rest = self.clone();
+        let mut slow = self.clone();
+        loop {
+            match rest {
+                Object::Atom(Atom::Nil) => return Some(items),
+                Object::Cons(cell) => {
+                    items.push(cell.car());
+                    rest = cell.cdr();
                 }
+                _ => return None,
             }
-            Token::Atom(atom) => {
-                return Object::Atom(atom.clone());
+            // `slow` moves at half the speed, so it meets `rest` if the list loops
+            if items.len() % 2 == 0 {
+                let Object::Cons(cell) = slow else { unreachable!() };
+                slow = cell.cdr();
+                if let (Object::Cons(a), Object::Cons(b)) = (&slow, &rest) {
+                    if Rc::ptr_eq(a, b) {
+                        return None;
+                    }
+                }
             }
-            Token::RParen => {
-                panic!("Unexpected right paren");
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseError {
+    /// A `)` with no matching `(`
+    UnbalancedParen(Span),
+    /// The input ended inside the form or string starting at the span
+    UnexpectedEof(Span),
+    InvalidLiteral { message: String, span: Span },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnbalancedParen(span) => write!(f, "Unexpected ) at {}", span),
+            ParseError::UnexpectedEof(span) => write!(f, "Unexpected end of input in form starting at {}", span),
+            ParseError::InvalidLiteral { message, span } => write!(f, "{} at {}", message, span),
+        }
+    }
+}
+
+/// A top level expression along with where it starts in its source
+#[derive(Debug, PartialEq)]
+pub struct Form {
+    pub object: Object,
+    pub span: Span,
+}
+
+/// Parses every top level expression in `source`
+pub fn parse_all(source: &str) -> Result<Vec<Object>, ParseError> {
+    let forms = parse_forms(&tokenize_spanned(source, None)?, false)?;
+    Ok(forms.into_iter().map(|form| form.object).collect())
+}
+
+/// Parses every top level expression in `source`, recording where each one starts. Where the
+/// lists inside them and their elements start is recorded too, for `list_span` and
+/// `symbol_span` to report where errors happen.
+pub fn parse_source(source: &str, file: Option<&str>) -> Result<Vec<Form>, ParseError> {
+    parse_forms(&tokenize_spanned(source, file)?, true)
+}
+
+fn parse_forms(tokens: &[(Token, Span)], record: bool) -> Result<Vec<Form>, ParseError> {
+    let mut token_iter = tokens.iter().peekable();
+    let mut forms = Vec::new();
+    while let Some((_, span)) = token_iter.peek() {
+        let span = span.clone();
+        let object = parse(&mut token_iter, record)?;
+        forms.push(Form { object, span });
+    }
+    Ok(forms)
+}
+
+/// Where a cons read by `parse_source` was written
+struct SourceSpans {
+    /// Held weakly so the table doesn't keep code alive. While it is held the cons's address
+    /// can't be reused, so the entry can't be mistaken for another cons's.
+    cell: Weak<ConsCell>,
+    /// Where the list opens, for the first cons of a list
+    list: Option<Span>,
+    /// Where the car starts
+    car: Span,
+}
+
+struct SpanTable {
+    spans: HashMap<usize, SourceSpans>,
+    /// The size at which entries for freed conses are next removed
+    prune_at: usize,
+}
+
+thread_local! {
+    static SOURCE_SPANS: RefCell<SpanTable> = RefCell::new(SpanTable { spans: HashMap::new(), prune_at: 1024 });
+}
+
+/// Records where a list read from source opens and where each of its elements starts
+fn record_spans(list: &Object, start: &Span, elements: Vec<Span>) {
+    SOURCE_SPANS.with(|table| {
+        let mut table = table.borrow_mut();
+        if table.spans.len() >= table.prune_at {
+            table.spans.retain(|_, spans| spans.cell.strong_count() > 0);
+            table.prune_at = (2 * table.spans.len()).max(1024);
+        }
+        let mut rest = list.clone();
+        let mut list = Some(start.clone());
+        for car in elements {
+            let Object::Cons(cell) = rest else { break };
+            table.spans.insert(gc::address(&cell), SourceSpans { cell: Rc::downgrade(&cell), list: list.take(), car });
+            rest = cell.cdr();
+        }
+    });
+}
+
+fn source_spans<T>(cell: &Rc<ConsCell>, get: impl FnOnce(&SourceSpans) -> Option<T>) -> Option<T> {
+    SOURCE_SPANS.with(|table| table.borrow().spans.get(&gc::address(cell)).and_then(get))
+}
+
+/// Where the list starting at `cell` opens, if it was read by `parse_source`
+pub fn list_span(cell: &Rc<ConsCell>) -> Option<Span> {
+    source_spans(cell, |spans| spans.list.clone())
+}
+
+/// Where `symbol` is first written in `form`, outside of quoted data, if `form` was read by
+/// `parse_source`
+pub fn symbol_span(form: &Object, symbol: &str) -> Option<Span> {
+    let mut pending = vec![form.clone()];
+    let mut seen = HashSet::new();
+    while let Some(object) = pending.pop() {
+        let Object::Cons(cell) = object else { continue };
+        if !seen.insert(gc::address(&cell)) {
+            continue;
+        }
+        let car = cell.car();
+        match &car {
+            Object::Atom(Atom::Symbol(name)) if name == symbol => {
+                if let Some(span) = source_spans(&cell, |spans| Some(spans.car.clone())) {
+                    return Some(span);
+                }
             }
+            Object::Atom(Atom::Symbol(name)) if name == "quote" && list_span(&cell).is_some() => continue,
+            _ => {}
         }
+        // The car is searched before the rest of the list
+        pending.push(cell.cdr());
+        pending.push(car);
     }
+    None
+}
 
-    Object::Atom(Atom::Nil)
+fn misplaced_dot(span: &Span) -> ParseError {
+    ParseError::InvalidLiteral { message: String::from("Dot must come between list elements"), span: span.clone() }
+}
+
+/// Parses a single expression from a non-empty token stream, recording where its lists and
+/// their elements start when `record` is set
+fn parse(token_iter: &mut Peekable<Iter<(Token, Span)>>, record: bool) -> Result<Object, ParseError> {
+    match token_iter.next() {
+        Some((Token::LParen, start)) => {
+            let mut list = Vec::new();
+            let mut spans = Vec::new();
+            let finish = |object: Object, spans: Vec<Span>| {
+                if record {
+                    record_spans(&object, start, spans);
+                }
+                Ok(object)
+            };
+            while let Some((token, span)) = token_iter.peek() {
+                match token {
+                    Token::RParen => {
+                        token_iter.next();
+                        return finish(Object::list(list), spans);
+                    }
+                    Token::Dot => {
+                        let (_, dot) = token_iter.next().unwrap();
+                        if list.is_empty() || matches!(token_iter.peek(), Some((Token::RParen | Token::Dot, _))) {
+                            return Err(misplaced_dot(dot));
+                        }
+                        let Some(_) = token_iter.peek() else { break };
+                        let tail = parse(token_iter, record)?;
+                        return match token_iter.next() {
+                            Some((Token::RParen, _)) => finish(Object::dotted_list(list, tail), spans),
+                            Some((_, span)) => Err(ParseError::InvalidLiteral {
+                                message: String::from("Expected ) after the last element of a dotted list"),
+                                span: span.clone(),
+                            }),
+                            None => Err(ParseError::UnexpectedEof(start.clone())),
+                        };
+                    }
+                    _ => {
+                        spans.push(span.clone());
+                        list.push(parse(token_iter, record)?);
+                    }
+                }
+            }
+            Err(ParseError::UnexpectedEof(start.clone()))
+        }
+        Some((Token::Atom(atom), _)) => Ok(Object::Atom(atom.clone())),
+        Some((Token::RParen, span)) => Err(ParseError::UnbalancedParen(span.clone())),
+        Some((Token::Dot, span)) => Err(misplaced_dot(span)),
+        None => unreachable!("parse called on an empty token stream"),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::parser::{Object, parse};
-    use crate::tokenizer::{Atom, tokenize_expr};
+    use crate::parser::{Object, ParseError, parse_all, parse_source};
+    use crate::tokenizer::{Atom, Span};
 
     #[test]
     fn parse_simple() {
-        let tokens = tokenize_expr("(+ 1 2)");
-        let parsed = parse(&mut tokens.iter().peekable());
-        assert_eq!(parsed, Object::List(vec![
+        let parsed = parse_all("(+ 1 2)").unwrap();
+        assert_eq!(parsed, vec![Object::list(vec![
             Object::Atom(Atom::Symbol("+".to_string())),
             Object::Atom(Atom::Integer(1)),
             Object::Atom(Atom::Integer(2)),
-        ]));
+        ])]);
     }
 
     #[test]
     fn parse_nested() {
-        let tokens = tokenize_expr("(+ 1 (* 2 3))");
-        let parsed = parse(&mut tokens.iter().peekable());
-        assert_eq!(parsed, Object::List(vec![
+        let parsed = parse_all("(+ 1 (* 2 3))").unwrap();
+        assert_eq!(parsed, vec![Object::list(vec![
             Object::Atom(Atom::Symbol("+".to_string())),
             Object::Atom(Atom::Integer(1)),
-            Object::List(vec![
+            Object::list(vec![
                 Object::Atom(Atom::Symbol("*".to_string())),
                 Object::Atom(Atom::Integer(2)),
                 Object::Atom(Atom::Integer(3)),
             ]),
-        ]));
+        ])]);
+    }
+
+    #[test]
+    fn parse_multiple() {
+        let parsed = parse_all("a (b) 'c").unwrap();
+        assert_eq!(parsed, vec![
+            Object::Atom(Atom::Symbol("a".to_string())),
+            Object::list(vec![Object::Atom(Atom::Symbol("b".to_string()))]),
+            Object::list(vec![
+                Object::Atom(Atom::Symbol("quote".to_string())),
+                Object::Atom(Atom::Symbol("c".to_string())),
+            ]),
+        ]);
+        assert_eq!(parse_all("  ;; nothing here\n"), Ok(vec![]));
+    }
+
+    #[test]
+    fn parse_forms_with_spans() {
+        let forms = parse_source("(a)\n  'b 5", Some("test.l")).unwrap();
+        let positions = forms.iter().map(|form| form.span.to_string()).collect::<Vec<_>>();
+        assert_eq!(positions, vec!["test.l:1:1", "test.l:2:3", "test.l:2:6"]);
+        assert_eq!(forms[2].object, Object::Atom(Atom::Integer(5)));
+    }
+
+    #[test]
+    fn parse_dotted() {
+        let symbol = |name: &str| Object::Atom(Atom::Symbol(name.to_string()));
+        assert_eq!(parse_all("(a . b)"), Ok(vec![Object::cons(symbol("a"), symbol("b"))]));
+        assert_eq!(parse_all("(a b . c)"), Ok(vec![Object::dotted_list(vec![symbol("a"), symbol("b")], symbol("c"))]));
+        assert_eq!(parse_all("(a . (b))"), parse_all("(a b)"));
+        assert_eq!(parse_all("(1 .5)"), parse_all("(1 0.5)"));
+
+        let span = |line, column| Span { file: None, line, column };
+        assert_eq!(parse_all("(a . b"), Err(ParseError::UnexpectedEof(span(1, 1))));
+        assert_eq!(parse_all("(a ."), Err(ParseError::UnexpectedEof(span(1, 1))));
+        assert!(matches!(parse_all("(. a)"), Err(ParseError::InvalidLiteral { .. })));
+        assert!(matches!(parse_all("(a .)"), Err(ParseError::InvalidLiteral { .. })));
+        assert!(matches!(parse_all("(a . b c)"), Err(ParseError::InvalidLiteral { .. })));
+        assert!(matches!(parse_all("."), Err(ParseError::InvalidLiteral { .. })));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn drop_deep_structure() {
+        // Nested through the car as well as the cdr, so neither direction may recurse
+        let mut object = Object::Atom(Atom::Nil);
+        for _ in 0..1_000_000 {
+            object = Object::cons(object, Object::Atom(Atom::Nil));
+        }
+        for _ in 0..1_000_000 {
+            object = Object::cons(Object::Atom(Atom::Nil), object);
+        }
+        drop(object);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let span = |line, column| Span { file: None, line, column };
+        assert_eq!(parse_all("(a))"), Err(ParseError::UnbalancedParen(span(1, 4))));
+        assert_eq!(parse_all("(a\n  (b c)"), Err(ParseError::UnexpectedEof(span(1, 1))));
+        assert_eq!(parse_all("'"), Err(ParseError::UnexpectedEof(span(1, 1))));
+        assert_eq!(parse_all("(a ')"), Err(ParseError::UnbalancedParen(span(1, 5))));
+        assert_eq!(parse_all("\"abc"), Err(ParseError::UnexpectedEof(span(1, 1))));
+        assert!(matches!(parse_all("(/ 1/0)"), Err(ParseError::InvalidLiteral { .. })));
+        assert!(matches!(parse_all("\"\\q\""), Err(ParseError::InvalidLiteral { .. })));
+        assert!(matches!(parse_all("#x"), Err(ParseError::InvalidLiteral { .. })));
+    }
+}
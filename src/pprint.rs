@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use crate::functions::{circular_conses, fn_print_helper};
+use crate::gc;
+use crate::interpreter::Environment;
+use crate::parser::Object;
+use crate::tokenizer::Atom;
+
+/// The right margin used when `*print-right-margin*` isn't set to a positive integer
+pub const DEFAULT_RIGHT_MARGIN: usize = 80;
+
+/// Forms written with their first few arguments on the opening line and the rest as a body
+/// indented by two columns, along with how many arguments go on the opening line
+const BODY_FORMS: &[(&str, usize)] = &[
+    ("defun", 2), ("defmacro", 2), ("lambda", 1), ("let", 1), ("let*", 1), ("flet", 1), ("labels", 1),
+    ("when", 1), ("unless", 1), ("progn", 0), ("dotimes", 1), ("dolist", 1), ("do", 2), ("do*", 2),
+    ("loop", 0), ("block", 1), ("catch", 1), ("unwind-protect", 1), ("handler-case", 1),
+    ("ignore-errors", 0),
+];
+
+/// The right margin set by `*print-right-margin*`
+pub fn right_margin(env: &Environment) -> usize {
+    match env.find("*print-right-margin*") {
+        Some(Object::Atom(Atom::Integer(margin))) if margin > 0 => margin as usize,
+        _ => DEFAULT_RIGHT_MARGIN,
+    }
+}
+
+/// Prints an object as `prin1` does, breaking lists over several indented lines where they
+/// don't fit within `margin` columns
+pub fn pretty_print(object: &Object, margin: usize) -> String {
+    let layout = Layout { margin, circular: circular_conses(object) };
+    layout.layout(object, 0)
+}
+
+fn width(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// The column just after `text` when it is written starting at `column`
+fn end_column(text: &str, column: usize) -> usize {
+    match text.rsplit_once('\n') {
+        Some((_, last)) => width(last),
+        None => column + width(text),
+    }
+}
+
+struct Layout {
+    margin: usize,
+    /// The conses that refer back to themselves, which are written on one line with their
+    /// labels rather than laid out without end
+    circular: HashSet<usize>,
+}
+
+impl Layout {
+    /// Lays out an object that starts at `column`. Atoms, dotted lists, circular lists and
+    /// anything that fits are written on one line.
+    fn layout(&self, object: &Object, column: usize) -> String {
+        let margin = self.margin;
+        let flat = fn_print_helper(object);
+        if column + width(&flat) <= margin {
+            return flat;
+        }
+        if let Object::Cons(cell) = object {
+            if self.circular.contains(&gc::address(cell)) {
+                return flat;
+            }
+        }
+        let Some(items) = object.to_vec().filter(|items| !items.is_empty()) else {
+            return flat;
+        };
+        let atoms = items[1..].iter().all(|item| !matches!(item, Object::Cons(_)));
+        match &items[0] {
+            Object::Atom(Atom::Symbol(name)) if !name.starts_with(':') => {
+                if let Some((_, arguments)) = BODY_FORMS.iter().find(|(form, _)| form == name) {
+                    return self.body_style(&items, *arguments, column);
+                }
+                // Calls, including `cond` and `if`, line their arguments up under the first one,
+                // unless the operator is so long that would leave little room
+                let align = column + width(name) + 2;
+                if align > margin / 2 {
+                    self.body_style(&items, 0, column)
+                } else if atoms {
+                    self.fill_style(&items, column, align)
+                } else {
+                    self.aligned_style(&items, column)
+                }
+            }
+            Object::Cons(_) => self.aligned_style(&items, column),
+            _ if atoms => self.fill_style(&items, column, column + 1),
+            _ => self.aligned_style(&items, column),
+        }
+    }
+
+    /// Writes the operator and its first `arguments` arguments on the opening line, then each
+    /// remaining item on its own line indented two columns past the opening parenthesis
+    fn body_style(&self, items: &[Object], arguments: usize, column: usize) -> String {
+        let opening = (arguments + 1).min(items.len());
+        let mut text = String::from("(");
+        let mut current = column + 1;
+        for (i, item) in items[..opening].iter().enumerate() {
+            if i > 0 {
+                text.push(' ');
+                current += 1;
+            }
+            let part = self.layout(item, current);
+            current = end_column(&part, current);
+            text.push_str(&part);
+        }
+        for item in &items[opening..] {
+            text.push('\n');
+            text.push_str(&" ".repeat(column + 2));
+            text.push_str(&self.layout(item, column + 2));
+        }
+        text.push(')');
+        text
+    }
+
+    /// Writes the first two items on the opening line and each remaining item on its own line
+    /// lined up under the second. Lists starting with a list line every item up under the first.
+    fn aligned_style(&self, items: &[Object], column: usize) -> String {
+        let mut text = self.layout(&items[0], column + 1);
+        let operator = !matches!(&items[0], Object::Cons(_));
+        let align = if operator { end_column(&text, column + 1) + 1 } else { column + 1 };
+        text.insert(0, '(');
+        for (i, item) in items[1..].iter().enumerate() {
+            if i == 0 && operator {
+                text.push(' ');
+            } else {
+                text.push('\n');
+                text.push_str(&" ".repeat(align));
+            }
+            text.push_str(&self.layout(item, align));
+        }
+        text.push(')');
+        text
+    }
+
+    /// Writes a list of atoms with as many on each line as fit, starting each new line at `align`
+    fn fill_style(&self, items: &[Object], column: usize, align: usize) -> String {
+        let mut text = String::from("(");
+        let mut current = column + 1;
+        for (i, item) in items.iter().enumerate() {
+            let part = fn_print_helper(item);
+            if i > 0 {
+                if current + 1 + width(&part) > self.margin {
+                    text.push('\n');
+                    text.push_str(&" ".repeat(align));
+                    current = align;
+                } else {
+                    text.push(' ');
+                    current += 1;
+                }
+            }
+            current += width(&part);
+            text.push_str(&part);
+        }
+        text.push(')');
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_all;
+
+    fn pretty(source: &str, margin: usize) -> String {
+        pretty_print(&parse_all(source).unwrap().remove(0), margin)
+    }
+
+    #[test]
+    fn short_forms_stay_on_one_line() {
+        assert_eq!(pretty("(a (b c) \"d\" . e)", 80), "(a (b c) \"d\" . e)");
+        assert_eq!(pretty("nil", 1), "NIL");
+    }
+
+    #[test]
+    fn special_forms_indent_their_bodies() {
+        let source = "(defun fact (n) (cond ((<= n 1) 1) (t (* n (fact (- n 1))))))";
+        assert_eq!(pretty(source, 30), "\
+(defun fact (n)
+  (cond ((<= n 1) 1)
+        (T (* n
+              (fact (- n 1))))))");
+
+        let source = "(let ((x 1) (y 2)) (print x) (print y))";
+        assert_eq!(pretty(source, 20), "\
+(let ((x 1) (y 2))
+  (print x)
+  (print y))");
+        assert_eq!(pretty(source, 15), "\
+(let ((x 1)
+      (y 2))
+  (print x)
+  (print y))");
+    }
+
+    #[test]
+    fn data_lists_fill_lines() {
+        assert_eq!(pretty("(1 2 3 4 5 6 7 8 9 10)", 12), "\
+(1 2 3 4 5 6
+ 7 8 9 10)");
+        assert_eq!(pretty("(list 1 2 3 4 5 6)", 12), "\
+(list 1 2 3
+      4 5 6)");
+        assert_eq!(pretty("((a . 1) (b . 2) (c . 3))", 12), "\
+((a . 1)
+ (b . 2)
+ (c . 3))");
+    }
+
+    #[test]
+    fn circular_lists_end() {
+        // A tree whose child points back at its parent through a car
+        let root = parse_all("(root-node-with-a-long-name extra-data nil)").unwrap().remove(0);
+        let child = Object::list(vec![parse_all("child-node").unwrap().remove(0), root.clone()]);
+        let Object::Cons(cell) = &root else { unreachable!() };
+        let Object::Cons(rest) = cell.cdr() else { unreachable!() };
+        let Object::Cons(last) = rest.cdr() else { unreachable!() };
+        last.set_car(Object::list(vec![child]));
+        assert_eq!(pretty_print(&root, 30), "#1=(root-node-with-a-long-name extra-data ((child-node #1#)))");
+
+        let outer = Object::list(vec![parse_all("tree").unwrap().remove(0), root.clone()]);
+        assert_eq!(pretty_print(&outer, 30), "(tree #1=(root-node-with-a-long-name extra-data ((child-node #1#))))");
+        last.set_car(Object::Atom(Atom::Nil));
+    }
+}
@@ -0,0 +1,175 @@
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::error::LispError;
+use crate::functions::fresh_line;
+use crate::interpreter::{eval, Environment, BUILTINS, SPECIAL_FORMS};
+use crate::parser::{self, ParseError};
+use crate::pprint::{pretty_print, right_margin};
+use crate::tokenizer::is_delimiter;
+
+/// Editor support for the REPL: completes symbols bound in the environment along with
+/// builtin names, and highlights the parenthesis matching the one under the cursor
+struct LispHelper {
+    env: Environment,
+    highlighter: MatchingBracketHighlighter,
+}
+
+impl LispHelper {
+    /// The sorted names starting with `prefix` that are bound, builtin or special forms
+    fn completions(&self, prefix: &str) -> Vec<String> {
+        let mut names = self.env.symbols();
+        names.extend(BUILTINS.iter().chain(SPECIAL_FORMS).map(|name| name.to_string()));
+        names.retain(|name| name.starts_with(prefix));
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// The byte index where the symbol ending at `pos` starts
+fn symbol_start(line: &str, pos: usize) -> usize {
+    line[..pos].char_indices().rev().find(|&(_, c)| is_delimiter(c)).map_or(0, |(index, c)| index + c.len_utf8())
+}
+
+impl Completer for LispHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = symbol_start(line, pos);
+        let prefix = line[start..pos].to_lowercase();
+        let candidates = self.completions(&prefix)
+            .into_iter()
+            .map(|name| Pair { display: name.clone(), replacement: name })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Highlighter for LispHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.highlighter.highlight(line, pos)
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.highlighter.highlight_char(line, pos, kind)
+    }
+}
+
+impl Hinter for LispHelper {
+    type Hint = String;
+}
+
+impl Validator for LispHelper {}
+
+impl Helper for LispHelper {}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".lisp_history"))
+}
+
+/// Reads lines until they hold complete forms, then evaluates and prints each form.
+/// Stops at the end of input. The history is saved however the REPL stops.
+pub fn run_repl(env: &Environment) -> rustyline::Result<()> {
+    let mut editor: Editor<LispHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(LispHelper { env: env.clone(), highlighter: MatchingBracketHighlighter::new() }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history file the first time the REPL runs
+        let _ = editor.load_history(path);
+    }
+
+    let result = read_eval_print(&mut editor, env);
+    match &history {
+        // Saved before looking at the result, so that an error doesn't lose the session
+        Some(path) => {
+            let saved = editor.save_history(path);
+            result.and(saved)
+        }
+        None => result,
+    }
+}
+
+fn read_eval_print(editor: &mut Editor<LispHelper, DefaultHistory>, env: &Environment) -> rustyline::Result<()> {
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                if let Err(error) = parser::parse_all(&input) {
+                    eprintln!("Error: {}", LispError::from(error));
+                }
+                break;
+            }
+            Err(error) => return Err(error),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+        input.push_str(&line);
+        input.push('\n');
+
+        // Parsed with positions so that errors show where in the input they happened
+        let forms = match parser::parse_source(&input, None) {
+            Ok(forms) => forms,
+            Err(ParseError::UnexpectedEof(_)) => continue,
+            Err(error) => {
+                eprintln!("Error: {}", LispError::from(error));
+                input.clear();
+                continue;
+            }
+        };
+        input.clear();
+        for form in forms {
+            let result = eval(&form.object, env);
+            fresh_line();
+            match result {
+                Ok(value) => println!("{}", pretty_print(&value, right_margin(env))),
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Object;
+    use crate::tokenizer::Atom;
+
+    #[test]
+    fn complete_symbols() {
+        let env = Environment::new();
+        env.define("carrot", Object::Atom(Atom::Integer(1)));
+        env.define_function("cardinal", Object::Atom(Atom::Nil));
+        let helper = LispHelper { env: env.extend(), highlighter: MatchingBracketHighlighter::new() };
+        assert_eq!(helper.completions("car"), vec!["car", "cardinal", "carrot"]);
+        assert_eq!(helper.completions("prog"), vec!["prog1", "prog2", "progn"]);
+        assert!(helper.completions("zzz").is_empty());
+    }
+
+    #[test]
+    fn symbol_starts() {
+        assert_eq!(symbol_start("(car", 4), 1);
+        assert_eq!(symbol_start("car", 3), 0);
+        assert_eq!(symbol_start("(list\u{a0}ca", 9), 7);
+        assert_eq!(symbol_start("\"λ\" ca", 7), 5);
+    }
+}
@@ -1,4 +1,14 @@
+use std::fmt;
+use std::iter::Peekable;
 use std::ops::Deref;
+use std::rc::Rc;
+use std::str::Chars;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+
+use crate::parser::ParseError;
+use crate::number::parse_number;
 
 #[derive(Debug, Copy, Clone)]
 pub struct MyFloat(pub f64);
@@ -22,6 +32,8 @@ impl Deref for MyFloat {
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Atom {
     Integer(i32),
+    BigInt(BigInt),
+    Ratio(BigRational),
     Float(MyFloat),
     Symbol(String),
     String(String),
@@ -30,86 +42,251 @@ pub enum Atom {
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Token {
     LParen,
     RParen,
+    /// The `.` before the last element of a dotted list
+    Dot,
     Atom(Atom),
 }
 
-pub fn tokenize_expr(line: &str) -> Vec<Token> {
-    let new_str = line.replace('(', " ( ").replace(')', " ) ").replace(";;", " ;; ").replace("\n", " <newline> ");
-    let mut word_iter = new_str.split_whitespace();
+fn parse_atom(w: &str) -> Atom {
+    if let Some(number) = parse_number(w) {
+        number.into_atom()
+    } else {
+        match w {
+            "T" | "t" => Atom::T,
+            "NIL" | "Nil" | "nil" => Atom::Nil,
+            _ => Atom::Symbol(w.to_lowercase().to_string()),
+        }
+    }
+}
+
+/// Whether a word is written like a ratio but has a zero denominator, e.g. `1/0`
+fn is_zero_ratio(w: &str) -> bool {
+    match w.split_once('/') {
+        Some((numer, denom)) => {
+            let numer = numer.strip_prefix(['+', '-']).unwrap_or(numer);
+            !numer.is_empty() && numer.bytes().all(|b| b.is_ascii_digit())
+                && !denom.is_empty() && denom.bytes().all(|b| b == b'0')
+        }
+        None => false,
+    }
+}
+
+pub fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';' | '\'' | '`' | ',')
+}
+
+/// Where a token starts in its source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+            None => write!(f, "{}:{}", self.line, self.column),
+        }
+    }
+}
 
-    let mut tokens: Vec<Token> = Vec::new();
+/// A character iterator that keeps track of the current line and column
+struct Scanner<'a> {
+    chars: Peekable<Chars<'a>>,
+    file: Option<Rc<str>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(source: &'a str, file: Option<&str>) -> Self {
+        Scanner { chars: source.chars().peekable(), file: file.map(Rc::from), line: 1, column: 1 }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    fn span(&self) -> Span {
+        Span { file: self.file.clone(), line: self.line, column: self.column }
+    }
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+}
+
+fn invalid_literal(span: &Span, message: &str) -> ParseError {
+    ParseError::InvalidLiteral { message: message.to_string(), span: span.clone() }
+}
+
+/// Closes the `(quote ...` forms whose quoted expression has just been completed
+fn close_quotes(tokens: &mut Vec<(Token, Span)>, quoted_depths: &mut Vec<i32>, span: &Span) {
+    while quoted_depths.last() == Some(&0) {
+        quoted_depths.pop();
+        tokens.push((Token::RParen, span.clone()));
+    }
+}
+
+/// Reads the body of a string literal after its opening quote, handling escapes
+fn read_string(chars: &mut Scanner, start: &Span) -> Result<String, ParseError> {
+    let mut string = String::new();
+    loop {
+        let span = chars.span();
+        match chars.next() {
+            None => return Err(ParseError::UnexpectedEof(start.clone())),
+            Some('"') => return Ok(string),
+            Some('\\') => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('u') => read_unicode_escape(chars, start, &span)?,
+                    Some(c) => return Err(invalid_literal(&span, &format!("Unknown escape \\{}", c))),
+                    None => return Err(ParseError::UnexpectedEof(start.clone())),
+                };
+                string.push(escaped);
+            }
+            Some(c) => string.push(c),
+        }
+    }
+}
+
+/// Reads the `{XXXX}` part of a `\u{XXXX}` escape
+fn read_unicode_escape(chars: &mut Scanner, start: &Span, span: &Span) -> Result<char, ParseError> {
+    if chars.next() != Some('{') {
+        return Err(invalid_literal(span, "Expected { after \\u"));
+    }
+    let mut hex = String::new();
+    for c in chars.by_ref() {
+        if c == '}' {
+            return u32::from_str_radix(&hex, 16).ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| invalid_literal(span, &format!("Invalid unicode escape \\u{{{}}}", hex)));
+        }
+        hex.push(c);
+    }
+    Err(ParseError::UnexpectedEof(start.clone()))
+}
+
+/// Tokenizes `source`, pairing every token with where it starts. Tokens inserted for
+/// quote syntax take the position of the quote character or of the quoted expression's end.
+pub fn tokenize_spanned(source: &str, file: Option<&str>) -> Result<Vec<(Token, Span)>, ParseError> {
+    let mut chars = Scanner::new(source, file);
+
+    let mut tokens: Vec<(Token, Span)> = Vec::new();
     let mut quoted_depths = Vec::new();
 
-    while let Some(w) = word_iter.next() {
-        match w {
-            "<newline>" => { continue; }
-            ";;" => {
-                while let Some(w) = word_iter.next() {
-                    if w == "<newline>" {
+    loop {
+        let span = chars.span();
+        let Some(c) = chars.next() else { break };
+        match c {
+            c if c.is_whitespace() => { continue; }
+            ';' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
                         break;
                     }
                 }
             }
-            "(" => {
-                tokens.push(Token::LParen);
-                if !quoted_depths.is_empty() {
-                    *quoted_depths.last_mut().unwrap() += 1;
+            '(' => {
+                tokens.push((Token::LParen, span));
+                if let Some(depth) = quoted_depths.last_mut() {
+                    *depth += 1;
                 }
             }
-            ")" => {
-                tokens.push(Token::RParen);
-                if !quoted_depths.is_empty() {
-                    *quoted_depths.last_mut().unwrap() -= 1;
+            ')' => {
+                if quoted_depths.last() == Some(&0) {
+                    return Err(ParseError::UnbalancedParen(span));
                 }
-                if !quoted_depths.is_empty() && quoted_depths[quoted_depths.len() - 1] == 0 {
-                    quoted_depths.pop();
-                    tokens.push(Token::RParen);
+                tokens.push((Token::RParen, span.clone()));
+                if let Some(depth) = quoted_depths.last_mut() {
+                    *depth -= 1;
                 }
+                close_quotes(&mut tokens, &mut quoted_depths, &span);
             }
-            "T" | "t" => { tokens.push(Token::Atom(Atom::T)); }
-            "NIL" | "Nil" | "nil" => { tokens.push(Token::Atom(Atom::Nil)); }
-            "'" => {
-                tokens.push(Token::LParen);
-                tokens.push(Token::Atom(Atom::Symbol("quote".to_string())));
+            '\'' | '`' | ',' => {
+                let name = match c {
+                    '\'' => "quote",
+                    '`' => "quasiquote",
+                    _ if chars.peek() == Some(&'@') => {
+                        chars.next();
+                        "unquote-splicing"
+                    }
+                    _ => "unquote",
+                };
+                tokens.push((Token::LParen, span.clone()));
+                tokens.push((Token::Atom(Atom::Symbol(name.to_string())), span));
                 quoted_depths.push(0);
             }
+            '#' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                tokens.push((Token::LParen, span.clone()));
+                tokens.push((Token::Atom(Atom::Symbol("function".to_string())), span));
+                quoted_depths.push(0);
+            }
+            '#' => {
+                return Err(invalid_literal(&span, "Unknown # syntax"));
+            }
+            '"' => {
+                tokens.push((Token::Atom(Atom::String(read_string(&mut chars, &span)?)), span.clone()));
+                close_quotes(&mut tokens, &mut quoted_depths, &span);
+            }
             _ => {
-                if !quoted_depths.is_empty() && quoted_depths[quoted_depths.len() - 1] == 0 {
-                    tokens.push(Token::Atom(Atom::Symbol(w.to_lowercase().to_string())));
-                    tokens.push(Token::RParen);
-                    quoted_depths.pop();
-                } else if let Ok(n) = w.parse::<i32>() {
-                    tokens.push(Token::Atom(Atom::Integer(n)));
-                } else if let Ok(n) = w.parse::<f64>() {
-                    tokens.push(Token::Atom(Atom::Float(MyFloat(n))));
-                } else if w.starts_with("'") {
-                    tokens.push(Token::LParen);
-                    tokens.push(Token::Atom(Atom::Symbol("quote".to_string())));
-                    tokens.push(Token::Atom(Atom::Symbol(w[1..].to_lowercase().to_string())));
-                    tokens.push(Token::RParen);
-                } else if w.starts_with('"') && w.ends_with('"') {
-                    tokens.push(Token::Atom(Atom::String(w.trim_matches('"').to_string())));
-                } else {
-                    tokens.push(Token::Atom(Atom::Symbol(w.to_lowercase().to_string())));
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if is_delimiter(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                if is_zero_ratio(&word) {
+                    return Err(invalid_literal(&span, &format!("Ratio with zero denominator {}", word)));
                 }
+                if word == "." {
+                    tokens.push((Token::Dot, span));
+                    continue;
+                }
+                tokens.push((Token::Atom(parse_atom(&word)), span.clone()));
+                close_quotes(&mut tokens, &mut quoted_depths, &span);
             }
         }
     }
 
-    tokens
+    Ok(tokens)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn tokenize_expr(source: &str) -> Result<Vec<Token>, ParseError> {
+        Ok(tokenize_spanned(source, None)?.into_iter().map(|(token, _)| token).collect())
+    }
+
     #[test]
     fn tokenize_simple() {
-        let tokens = tokenize_expr("(+ 1 2)");
+        let tokens = tokenize_expr("(+ 1 2)").unwrap();
         assert_eq!(tokens, vec![
             Token::LParen,
             Token::Atom(Atom::Symbol("+".to_string())),
@@ -121,7 +298,7 @@ mod tests {
 
     #[test]
     fn tokenize_nested() {
-        let tokens = tokenize_expr("(+ 1 (* 2 3)) ;;comment");
+        let tokens = tokenize_expr("(+ 1 (* 2 3)) ;;comment").unwrap();
         assert_eq!(tokens, vec![
             Token::LParen,
             Token::Atom(Atom::Symbol("+".to_string())),
@@ -137,7 +314,7 @@ mod tests {
 
     #[test]
     fn tokenize_quoted() {
-        let tokens = tokenize_expr("'a");
+        let tokens = tokenize_expr("'a").unwrap();
         assert_eq!(tokens, vec![
             Token::LParen,
             Token::Atom(Atom::Symbol(String::from("quote"))),
@@ -145,7 +322,7 @@ mod tests {
             Token::RParen,
         ]);
 
-        let tokens = tokenize_expr("'(1 2.5)");
+        let tokens = tokenize_expr("'(1 2.5)").unwrap();
         assert_eq!(tokens, vec![
             Token::LParen,
             Token::Atom(Atom::Symbol(String::from("quote"))),
@@ -157,9 +334,21 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn tokenize_dot() {
+        let tokens = tokenize_expr("(a . .5)").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("a"))),
+            Token::Dot,
+            Token::Atom(Atom::Float(MyFloat(0.5))),
+            Token::RParen,
+        ]);
+    }
+
     #[test]
     fn tokenize_double_quote() {
-        let tokens = tokenize_expr("'(car '(1))");
+        let tokens = tokenize_expr("'(car '(1))").unwrap();
         assert_eq!(tokens, vec![
             Token::LParen,
             Token::Atom(Atom::Symbol(String::from("quote"))),
@@ -175,4 +364,120 @@ mod tests {
             Token::RParen,
         ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tokenize_function_quote() {
+        let tokens = tokenize_expr("#'car").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("function"))),
+            Token::Atom(Atom::Symbol(String::from("car"))),
+            Token::RParen,
+        ]);
+
+        let tokens = tokenize_expr("#'(lambda (x) x)").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("function"))),
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("lambda"))),
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("x"))),
+            Token::RParen,
+            Token::Atom(Atom::Symbol(String::from("x"))),
+            Token::RParen,
+            Token::RParen,
+        ]);
+    }
+
+    #[test]
+    fn tokenize_numbers() {
+        let tokens = tokenize_expr("(123456789012345678901234567890 3/4 6/3 '1)").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::BigInt("123456789012345678901234567890".parse().unwrap())),
+            Token::Atom(Atom::Ratio(BigRational::new(BigInt::from(3), BigInt::from(4)))),
+            Token::Atom(Atom::Integer(2)),
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("quote"))),
+            Token::Atom(Atom::Integer(1)),
+            Token::RParen,
+            Token::RParen,
+        ]);
+    }
+
+    #[test]
+    fn tokenize_strings() {
+        let tokens = tokenize_expr(r#"(print "hello (world) ;; not a comment")"#).unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("print"))),
+            Token::Atom(Atom::String(String::from("hello (world) ;; not a comment"))),
+            Token::RParen,
+        ]);
+
+        let tokens = tokenize_expr(r#""a\n\t\"b\"\\ \u{1F600}""#).unwrap();
+        assert_eq!(tokens, vec![Token::Atom(Atom::String(String::from("a\n\t\"b\"\\ \u{1F600}")))]);
+
+        let tokens = tokenize_expr(r#"'"Hi""#).unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("quote"))),
+            Token::Atom(Atom::String(String::from("Hi"))),
+            Token::RParen,
+        ]);
+    }
+
+    #[test]
+    fn tokenize_string_errors() {
+        assert!(tokenize_expr(r#""abc"#).is_err());
+        assert!(tokenize_expr(r#""\q""#).is_err());
+        assert!(tokenize_expr(r#""\u{110000}""#).is_err());
+    }
+
+    #[test]
+    fn tokenize_nested_quotes() {
+        let tokens = tokenize_expr("''a").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("quote"))),
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("quote"))),
+            Token::Atom(Atom::Symbol(String::from("a"))),
+            Token::RParen,
+            Token::RParen,
+        ]);
+    }
+
+    #[test]
+    fn tokenize_quasiquote() {
+        let tokens = tokenize_expr("`(a ,b ,@c)").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("quasiquote"))),
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("a"))),
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("unquote"))),
+            Token::Atom(Atom::Symbol(String::from("b"))),
+            Token::RParen,
+            Token::LParen,
+            Token::Atom(Atom::Symbol(String::from("unquote-splicing"))),
+            Token::Atom(Atom::Symbol(String::from("c"))),
+            Token::RParen,
+            Token::RParen,
+            Token::RParen,
+        ]);
+    }
+
+    #[test]
+    fn tokenize_spans() {
+        let tokens = tokenize_spanned("(car\n  'x) \"a\nb\" y", Some("test.l")).unwrap();
+        let positions = tokens.iter().map(|(_, span)| (span.line, span.column)).collect::<Vec<_>>();
+        assert_eq!(positions, vec![(1, 1), (1, 2), (2, 3), (2, 3), (2, 4), (2, 4), (2, 5), (2, 7), (3, 4)]);
+        assert_eq!(tokens[2].1.to_string(), "test.l:2:3");
+
+        let error = tokenize_spanned("(print\n  \"abc)", Some("test.l")).unwrap_err();
+        assert_eq!(error.to_string(), "Unexpected end of input in form starting at test.l:2:3");
+    }
+}